@@ -0,0 +1,192 @@
+use argh::FromArgs;
+use evdev_rs::GrabMode;
+use sc2remap::config::Config;
+use sc2remap::error::{Error, UINPUT_PATH};
+use sc2remap::{discover, pidfile};
+use std::ffi::CString;
+use std::fmt::Display;
+use std::path::Path;
+
+#[derive(FromArgs)]
+/// Check the environment for common problems, such as missing permissions or a stale pid file.
+#[argh(subcommand, name = "doctor")]
+pub struct Doctor {}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: &str, detail: impl Display) {
+        println!("PASS {}: {}", check, detail);
+    }
+
+    fn fail(&mut self, check: &str, detail: impl Display) {
+        println!("FAIL {}: {}", check, detail);
+        self.failures += 1;
+    }
+}
+
+impl Doctor {
+    pub fn run(self, config: Option<&Path>, instance: Option<&str>) -> Result<(), Error> {
+        let mut report = Report::default();
+        check_config(&mut report, config);
+        check_uinput(&mut report);
+        check_group(&mut report);
+        check_devices(&mut report);
+        check_pid_file(&mut report, instance);
+        match report.failures {
+            0 => Ok(()),
+            failures => Err(Error::ChecksFailed(failures)),
+        }
+    }
+}
+
+fn check_config(report: &mut Report, config: Option<&Path>) {
+    match Config::load(config) {
+        Ok(config) => report.pass("config", format!("{} rules", config.rules.len())),
+        Err(e) => report.fail("config", e),
+    }
+}
+
+fn check_uinput(report: &mut Report) {
+    if !Path::new(UINPUT_PATH).exists() {
+        return report.fail(
+            "uinput",
+            format!(
+                "{} doesn't exist; load the uinput module with `sudo modprobe uinput`",
+                UINPUT_PATH
+            ),
+        );
+    }
+    match std::fs::OpenOptions::new().write(true).open(UINPUT_PATH) {
+        Ok(_) => report.pass("uinput", format!("{} is writable", UINPUT_PATH)),
+        Err(e) => report.fail("uinput", Error::open(Path::new(UINPUT_PATH), e)),
+    }
+}
+
+fn check_group(report: &mut Report) {
+    match group_id("input") {
+        None => report.fail("input group", "there is no input group"),
+        Some(gid) if in_group(gid) => report.pass("input group", "member"),
+        Some(_) => report.fail(
+            "input group",
+            "not a member; add yourself with `sudo usermod -aG input $USER` and log in again",
+        ),
+    }
+}
+
+/// Checks that every device node can be read, and that none is grabbed by another process, which
+/// would hide its events from sc2remap.
+fn check_devices(report: &mut Report) {
+    let paths = discover::event_devices();
+    let mut unreadable = Vec::new();
+    let mut grabbed = Vec::new();
+    for path in paths.iter() {
+        let mut device = match evdev_rs::Device::new_from_path(path) {
+            Ok(device) => device,
+            Err(e) => {
+                unreadable.push(Error::open(path, e));
+                continue;
+            }
+        };
+        match device.grab(GrabMode::Grab) {
+            Ok(()) => {
+                if let Err(e) = device.grab(GrabMode::Ungrab) {
+                    log::warn!("failed to ungrab {:?}: {}", path, e);
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => grabbed.push(path),
+            Err(e) => log::warn!("failed to grab {:?}: {}", path, e),
+        }
+    }
+    match unreadable.first() {
+        None => report.pass(
+            "device nodes",
+            format!("all {} event devices are readable", paths.len()),
+        ),
+        Some(e) => report.fail(
+            "device nodes",
+            format!(
+                "{} of {} event devices can't be opened, e.g. {}",
+                unreadable.len(),
+                paths.len(),
+                e
+            ),
+        ),
+    }
+    if grabbed.is_empty() {
+        report.pass("grabs", "no device is grabbed by another process");
+    } else {
+        report.fail(
+            "grabs",
+            format!(
+                "{:?} grabbed by another process, possibly another remapper",
+                grabbed
+            ),
+        );
+    }
+}
+
+fn check_pid_file(report: &mut Report, instance: Option<&str>) {
+    let runtime_dir = pidfile::runtime_dir();
+    if runtime_dir.is_dir() {
+        report.pass("runtime dir", format!("{:?} exists", runtime_dir));
+    } else {
+        report.fail(
+            "runtime dir",
+            format!(
+                "{:?} doesn't exist; it is normally created when you log in, and can be changed \
+                 by setting XDG_RUNTIME_DIR",
+                runtime_dir
+            ),
+        );
+    }
+    let pid_file = pidfile::path(instance);
+    if !pid_file.exists() {
+        return report.pass("pid file", "no other instance is running");
+    }
+    match pidfile::owner(&pid_file) {
+        Some(pid) => report.fail(
+            "pid file",
+            format!(
+                "another instance is running as pid {}; pass a different --instance to run \
+                 alongside it",
+                pid
+            ),
+        ),
+        None => report.pass(
+            "pid file",
+            format!(
+                "{:?} is stale, its process is gone; it will be removed on startup",
+                pid_file
+            ),
+        ),
+    }
+}
+
+fn group_id(name: &str) -> Option<libc::gid_t> {
+    let name = CString::new(name).ok()?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        None
+    } else {
+        Some(unsafe { (*group).gr_gid })
+    }
+}
+
+/// Whether this process is in the group `gid`. Groups added since logging in don't count, because
+/// they don't apply until the next login.
+fn in_group(gid: libc::gid_t) -> bool {
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+    let len = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if len < 0 {
+        return false;
+    }
+    let mut groups = vec![0; len as usize];
+    let len = unsafe { libc::getgroups(len, groups.as_mut_ptr()) };
+    len >= 0 && groups[..len as usize].contains(&gid)
+}
@@ -0,0 +1,56 @@
+use argh::FromArgs;
+use evdev_rs::enums::EventType;
+use sc2remap::devices::Identity;
+use sc2remap::discover;
+
+const EVENT_TYPES: [EventType; 9] = [
+    EventType::EV_KEY,
+    EventType::EV_REL,
+    EventType::EV_ABS,
+    EventType::EV_MSC,
+    EventType::EV_SW,
+    EventType::EV_LED,
+    EventType::EV_SND,
+    EventType::EV_REP,
+    EventType::EV_FF,
+];
+
+#[derive(FromArgs)]
+/// List evdev devices with their capabilities, to help write device matchers.
+#[argh(subcommand, name = "list-devices")]
+pub struct ListDevices {}
+
+impl ListDevices {
+    pub fn run(self) {
+        for path in discover::event_devices() {
+            let device = match evdev_rs::Device::new_from_path(&path) {
+                Ok(device) => device,
+                Err(e) => {
+                    println!("{}: failed to open: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let Identity {
+                name,
+                vendor_id,
+                product_id,
+                phys,
+            } = Identity::of(&device);
+            println!("{}: {:?}", path.display(), name.unwrap_or_default());
+            println!("  id: {:04x}:{:04x}", vendor_id, product_id);
+            println!("  phys: {}", phys.unwrap_or_default());
+            for link in discover::by_id_links(&path) {
+                println!("  by_id: {}", link);
+            }
+            println!("  heuristic: {}", discover::classify(&device));
+            for event_type in EVENT_TYPES.iter() {
+                let codes = discover::supported_codes(&device, *event_type);
+                if codes.is_empty() {
+                    continue;
+                }
+                let codes: Vec<_> = codes.iter().map(ToString::to_string).collect();
+                println!("  {}: {}", event_type, codes.join(" "));
+            }
+        }
+    }
+}
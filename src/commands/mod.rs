@@ -0,0 +1,34 @@
+mod doctor;
+mod list_devices;
+mod monitor;
+mod record;
+mod replay;
+
+use argh::FromArgs;
+use sc2remap::error::Error;
+use std::path::Path;
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Doctor(doctor::Doctor),
+    ListDevices(list_devices::ListDevices),
+    Monitor(monitor::Monitor),
+    Record(record::Record),
+    Replay(replay::Replay),
+}
+
+impl Command {
+    pub fn run(self, config: Option<&Path>, instance: Option<&str>) -> Result<(), Error> {
+        match self {
+            Command::Doctor(doctor) => doctor.run(config, instance),
+            Command::ListDevices(list_devices) => {
+                list_devices.run();
+                Ok(())
+            }
+            Command::Monitor(monitor) => monitor.run(config),
+            Command::Record(record) => record.run(config),
+            Command::Replay(replay) => replay.run(config),
+        }
+    }
+}
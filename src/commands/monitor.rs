@@ -0,0 +1,81 @@
+use argh::FromArgs;
+use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::InputEvent;
+use evdev_utils::AsyncDevice;
+use futures::StreamExt as _;
+use sc2remap::config::Config;
+use sc2remap::error::Error;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+#[derive(FromArgs)]
+/// Print live events from a device, and the configured rules they would fire.
+#[argh(subcommand, name = "monitor")]
+pub struct Monitor {
+    /// path of the device to read; it is not grabbed
+    #[argh(positional)]
+    path: PathBuf,
+
+    /// only print events of this type, e.g. EV_KEY; may be repeated
+    #[argh(option, short = 't', from_str_fn(parse_event_type))]
+    event_type: Vec<EventType>,
+
+    /// the device name rules see the events as coming from
+    #[argh(option, short = 's', default = "String::from(\"mouse\")")]
+    source: String,
+}
+
+fn parse_event_type(name: &str) -> Result<EventType, String> {
+    EventType::from_str(name).ok_or_else(|| format!("unknown event type {:?}", name))
+}
+
+impl Monitor {
+    pub fn run(self, config: Option<&Path>) -> Result<(), Error> {
+        let Self {
+            path,
+            event_type,
+            source,
+        } = self;
+        let config = Config::load(config)?;
+        let mut device = AsyncDevice::new(&path).map_err(|e| Error::open(&path, e))?;
+
+        let mut held = HashSet::new();
+        futures::executor::block_on(async {
+            while let Some(event) = device.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => return Err(Error::io(format!("failed to read {:?}", path), e)),
+                };
+                let InputEvent {
+                    time,
+                    event_code,
+                    value,
+                } = event;
+                if let EventCode::EV_KEY(_) = event_code {
+                    if value == 0 {
+                        let _: bool = held.remove(&event_code);
+                    } else {
+                        let _: bool = held.insert(event_code);
+                    }
+                }
+                if !event_type.is_empty()
+                    && !event_type
+                        .iter()
+                        .any(|event_type| event.is_type(event_type))
+                {
+                    continue;
+                }
+                println!(
+                    "{}.{:06} {} {}",
+                    time.tv_sec, time.tv_usec, event_code, value
+                );
+                for (i, rule) in config.rules.iter().enumerate() {
+                    if rule.matches(&source, &event, |code| held.contains(code)) {
+                        println!("  fires rule {}: {}", i, rule);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
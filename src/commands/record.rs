@@ -0,0 +1,65 @@
+use argh::FromArgs;
+use futures::StreamExt as _;
+use sc2remap::config::Config;
+use sc2remap::devices::Devices;
+use sc2remap::error::Error;
+use sc2remap::recording::Record as RecordedEvent;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+#[derive(FromArgs)]
+/// Record events from the configured devices to a file, one JSON object per line.
+#[argh(subcommand, name = "record")]
+pub struct Record {
+    /// file to write the recording to
+    #[argh(positional)]
+    path: PathBuf,
+
+    /// only record from the device with this name, e.g. mouse; may be repeated
+    #[argh(option, short = 'd')]
+    device: Vec<String>,
+}
+
+impl Record {
+    pub fn run(self, config: Option<&Path>) -> Result<(), Error> {
+        let Self { path, device } = self;
+        let config = Config::load(config)?;
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(&path)
+                .map_err(|e| Error::io(format!("failed to create {:?}", path), e))?,
+        );
+
+        let mut devices = Devices::default();
+        devices.watch_config(
+            &config,
+            |name| device.is_empty() || device.iter().any(|device| device == name),
+            false,
+            // Signals are left to kill the process, which is fine since every line is flushed.
+            &mut futures::stream::pending(),
+        )?;
+        log::info!("recording to {:?}", path);
+        futures::executor::block_on(async {
+            while let Some((source, event)) = devices.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::warn!("stopped recording {}: {:?}", source, e);
+                        devices.detach(&source);
+                        continue;
+                    }
+                };
+                let record = match RecordedEvent::new(&source, &event) {
+                    Some(record) => record,
+                    None => continue,
+                };
+                serde_json::to_writer(&mut file, &record)
+                    .map_err(std::io::Error::from)
+                    // Flush every line so that the recording survives being interrupted.
+                    .and_then(|()| writeln!(file))
+                    .and_then(|()| file.flush())
+                    .map_err(|e| Error::io(format!("failed to write {:?}", path), e))?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,88 @@
+use argh::FromArgs;
+use evdev_rs::InputEvent;
+use sc2remap::config::Config;
+use sc2remap::error::Error;
+use sc2remap::recording::Record;
+use sc2remap::remap::Remapper;
+use std::io::BufRead as _;
+use std::path::{Path, PathBuf};
+
+#[derive(FromArgs)]
+/// Feed a recording through the remapper, printing the events it would write to sc2input.
+#[argh(subcommand, name = "replay")]
+pub struct Replay {
+    /// recording to replay
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+impl Replay {
+    pub fn run(self, config: Option<&Path>) -> Result<(), Error> {
+        let Self { path } = self;
+        let config = Config::load(config)?;
+        let file = std::fs::File::open(&path).map_err(|e| Error::open(&path, e))?;
+
+        let mut remapper = Remapper::new(&config);
+        let mut sources = Vec::new();
+        for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| Error::io(format!("failed to read {:?}", path), e))?;
+            let record: Record = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("line {}: invalid record: {}", i + 1, e);
+                    continue;
+                }
+            };
+            let event = match record.event() {
+                Some(event) => event,
+                None => {
+                    eprintln!(
+                        "line {}: unknown event {} {}",
+                        i + 1,
+                        record.event_type,
+                        record.code
+                    );
+                    continue;
+                }
+            };
+            let InputEvent {
+                time,
+                event_code,
+                value,
+            } = event;
+            println!(
+                "{}.{:06} {} {} {}",
+                time.tv_sec, time.tv_usec, record.source, event_code, value
+            );
+            for output in remapper.process(&record.source, config.grabs(&record.source), event) {
+                println!("  -> {}", output);
+            }
+            if !sources.contains(&record.source) {
+                sources.push(record.source);
+            }
+        }
+        // Let the delayed rules, e.g. sequences and tap-holds, run out as they would have, except
+        // repeats, which would go on for as long as their triggers are held.
+        remapper.stop_repeating();
+        while let Some(deadline) = remapper.next_deadline() {
+            println!(
+                "{}.{:06} tick",
+                deadline.as_secs(),
+                deadline.subsec_micros()
+            );
+            for output in remapper.tick(deadline) {
+                println!("  -> {}", output);
+            }
+            // Presses released from the chord buffer may have started repeating.
+            remapper.stop_repeating();
+        }
+        // The devices went away with the end of the recording, which releases whatever they held.
+        for source in sources {
+            println!("end of {}", source);
+            for output in remapper.detach(&source) {
+                println!("  -> {}", output);
+            }
+        }
+        Ok(())
+    }
+}
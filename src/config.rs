@@ -0,0 +1,790 @@
+use crate::discover::{self, Matcher};
+use evdev_rs::enums::{EventCode, EventType, EV_KEY, EV_REL};
+use evdev_rs::InputEvent;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+/// Bindings used when no config file exists at the default location.
+const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The config parsed, but is inconsistent, e.g. a rule refers to a layer which doesn't exist.
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read(path, e) => write!(f, "failed to read config {:?}: {}", path, e),
+            Error::Parse(path, e) => write!(f, "failed to parse config {:?}: {}", path, e),
+            Error::Invalid(path, e) => write!(f, "invalid config {:?}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Selects the mouse instead of identifying it from the events it generates.
+    #[serde(default)]
+    pub mouse: Matcher,
+    /// Selects the keyboard instead of identifying it from the events it generates.
+    #[serde(default)]
+    pub keyboard: Matcher,
+    /// Devices to watch in addition to the mouse and keyboard.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Layers of rules which take precedence over the base rules while active.
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    /// Combinations of keys and buttons pressed together, which replace their individual outputs.
+    #[serde(default)]
+    pub chords: Vec<Chord>,
+    /// The identity and capabilities of sc2input.
+    #[serde(default)]
+    pub uinput: Uinput,
+    #[serde(default)]
+    pub wheel: Wheel,
+}
+
+/// How wheel events are turned into detents.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Wheel {
+    /// The most times a single wheel event fires a rule, however many detents it covers.
+    pub max_detents: u32,
+    /// Count detents from the hi-res wheel events of the devices which have them, rather than from
+    /// REL_WHEEL and REL_HWHEEL.
+    pub hi_res: bool,
+    /// The hi-res units which make a detent when `hi_res` is set; a physical detent is 120.
+    pub hi_res_threshold: i32,
+}
+
+impl Default for Wheel {
+    fn default() -> Self {
+        Self {
+            max_detents: 5,
+            hi_res: false,
+            hi_res_threshold: 120,
+        }
+    }
+}
+
+/// Whether `code` is a wheel, whose events may cover several detents at once.
+pub fn is_wheel(code: &EventCode) -> bool {
+    matches!(
+        code,
+        EventCode::EV_REL(EV_REL::REL_WHEEL) | EventCode::EV_REL(EV_REL::REL_HWHEEL)
+    )
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Uinput {
+    pub name: String,
+    /// The vendor and product IDs in hex, e.g. `0001:0001`.
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: (u16, u16),
+    /// The bus type, e.g. 3 for USB or 6 for a virtual device.
+    pub bustype: u16,
+    /// The key, button and REL codes to enable for the rules, by default exactly those they can
+    /// emit. The codes of the grabbed devices, whose events are forwarded, are always enabled too.
+    #[serde(deserialize_with = "deserialize_some_codes")]
+    pub codes: Option<Vec<EventCode>>,
+}
+
+impl Default for Uinput {
+    fn default() -> Self {
+        Self {
+            name: "sc2input".to_string(),
+            id: (1, 1),
+            bustype: 3,
+            codes: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    /// The name rules use to refer to this device.
+    pub name: String,
+    #[serde(rename = "match")]
+    pub matcher: Matcher,
+    /// Whether to grab the device; its events are then forwarded through sc2input.
+    #[serde(default)]
+    pub grab: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    /// The name rules use to refer to this layer.
+    pub name: String,
+    /// The key, button or REL event which activates the layer. Held layers need a key or button.
+    #[serde(deserialize_with = "deserialize_code")]
+    pub activate: EventCode,
+    #[serde(default)]
+    pub mode: LayerMode,
+    /// If set, only events from the device with this name activate the layer.
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    /// Active while `activate` is held.
+    #[default]
+    Hold,
+    /// Each press of `activate` switches the layer on or off.
+    Toggle,
+    /// Active until the next key or button press, or until the next event which fires one of the
+    /// layer's rules.
+    OneShot,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Inject a press and release of the output key.
+    #[default]
+    Press,
+    /// Inject the output key with the same value as the trigger.
+    Follow,
+    /// Inject a press and release of the output key if the trigger is released within
+    /// `timeout_ms`, and otherwise hold `hold` or activate `hold_layer` until it is released.
+    TapHold,
+    /// Inject a press and release of the output key, then again every `repeat_interval_ms` after
+    /// `repeat_delay_ms`, until the trigger is released.
+    Repeat,
+}
+
+/// What a tap-hold rule does when another key or button is pressed before it is resolved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interrupt {
+    /// Resolve it as a hold right away, so that the other key is modified.
+    #[default]
+    Hold,
+    /// Resolve it as a tap right away, before the other key.
+    Tap,
+    /// Keep waiting for the timeout.
+    Ignore,
+}
+
+fn default_timeout_ms() -> u64 {
+    200
+}
+
+fn default_repeat_delay_ms() -> u64 {
+    250
+}
+
+fn default_repeat_interval_ms() -> u64 {
+    50
+}
+
+/// A step of a sequence. A bare key name in a sequence is a tap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// A press and release of the key.
+    Tap(#[serde(deserialize_with = "deserialize_key")] EV_KEY),
+    Press(#[serde(deserialize_with = "deserialize_key")] EV_KEY),
+    Release(#[serde(deserialize_with = "deserialize_key")] EV_KEY),
+    /// A pause before the next step, in milliseconds.
+    DelayMs(u64),
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Tap(key) => write!(f, "{:?}", key),
+            Step::Press(key) => write!(f, "press {:?}", key),
+            Step::Release(key) => write!(f, "release {:?}", key),
+            Step::DelayMs(delay) => write!(f, "{}ms", delay),
+        }
+    }
+}
+
+fn default_window_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Chord {
+    /// The keys and buttons which must all be pressed, on any devices.
+    #[serde(deserialize_with = "deserialize_codes")]
+    pub inputs: Vec<EventCode>,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub output: EV_KEY,
+    /// Either press, or follow to hold the output until any of the inputs is released.
+    #[serde(default)]
+    pub mode: Mode,
+    /// How soon after the first input the others must be pressed.
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+}
+
+impl std::fmt::Display for Chord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inputs: Vec<_> = self.inputs.iter().map(ToString::to_string).collect();
+        write!(
+            f,
+            "{} within {}ms -> {:?} ({:?})",
+            inputs.join(" + "),
+            self.window_ms,
+            self.output,
+            self.mode
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(deserialize_with = "deserialize_code")]
+    pub trigger: EventCode,
+    /// If set, only events with exactly this value fire the rule.
+    #[serde(default)]
+    pub value: Option<i32>,
+    /// The key to inject. Exactly one of `output` and `sequence` must be set.
+    #[serde(default, deserialize_with = "deserialize_some_key")]
+    pub output: Option<EV_KEY>,
+    /// Steps to run one after the other, in press mode.
+    #[serde(default, deserialize_with = "deserialize_steps")]
+    pub sequence: Vec<Step>,
+    /// Whether to stop the sequence when the trigger is released, releasing the keys it holds.
+    #[serde(default)]
+    pub cancel_on_release: bool,
+    #[serde(default)]
+    pub mode: Mode,
+    /// The rule only fires while all of these keys or buttons are held, on any device.
+    #[serde(default, deserialize_with = "deserialize_codes")]
+    pub when_held: Vec<EventCode>,
+    /// The rule does not fire while any of these keys or buttons are held, on any device.
+    #[serde(default, deserialize_with = "deserialize_codes")]
+    pub unless_held: Vec<EventCode>,
+    /// If set, only events from the device with this name fire the rule.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// The layer the rule belongs to, or `None` for the base layer.
+    #[serde(default)]
+    pub layer: Option<String>,
+    /// The key held while the trigger is held, in tap-hold mode.
+    #[serde(default, deserialize_with = "deserialize_some_key")]
+    pub hold: Option<EV_KEY>,
+    /// The layer active while the trigger is held, in tap-hold mode.
+    #[serde(default)]
+    pub hold_layer: Option<String>,
+    /// How long the trigger must be held to count as a hold, in tap-hold mode.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub interrupt: Interrupt,
+    /// How long after the first press the output starts repeating, in repeat mode.
+    #[serde(default = "default_repeat_delay_ms")]
+    pub repeat_delay_ms: u64,
+    /// How often the output repeats, in repeat mode.
+    #[serde(default = "default_repeat_interval_ms")]
+    pub repeat_interval_ms: u64,
+    /// The most each interval is randomly lengthened or shortened by, in repeat mode.
+    #[serde(default)]
+    pub repeat_jitter_ms: u64,
+    /// Limits how often the rule fires, in press mode.
+    #[serde(default)]
+    pub limit: Limit,
+}
+
+fn default_per_ms() -> u64 {
+    1000
+}
+
+/// Limits on how often a rule fires, e.g. to keep a free-spinning wheel from flooding the game
+/// with presses. Events which would exceed a limit are dropped.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    /// The least time between two firings.
+    #[serde(default)]
+    pub min_interval_ms: Option<u64>,
+    /// The most firings in any `per_ms`.
+    #[serde(default)]
+    pub max_presses: Option<usize>,
+    #[serde(default = "default_per_ms")]
+    pub per_ms: u64,
+    /// Collapses bursts into their first firing: the rule doesn't fire again until it has gone
+    /// this long without being triggered.
+    #[serde(default)]
+    pub collapse_ms: Option<u64>,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Self {
+            min_interval_ms: None,
+            max_presses: None,
+            per_ms: default_per_ms(),
+            collapse_ms: None,
+        }
+    }
+}
+
+impl Limit {
+    pub fn is_empty(&self) -> bool {
+        self.min_interval_ms.is_none() && self.max_presses.is_none() && self.collapse_ms.is_none()
+    }
+}
+
+impl Rule {
+    /// Whether `event` from `source` fires the rule, where `is_held` says whether a key or button
+    /// is currently held on any device.
+    pub fn matches<F>(&self, source: &str, event: &InputEvent, is_held: F) -> bool
+    where
+        F: Fn(&EventCode) -> bool,
+    {
+        let InputEvent {
+            time: _,
+            event_code,
+            value,
+        } = event;
+        if *event_code != self.trigger {
+            return false;
+        }
+        if self
+            .device
+            .as_deref()
+            .is_some_and(|device| device != source)
+        {
+            return false;
+        }
+        let value_matches = match (self.value, self.mode) {
+            // A wheel event covering several detents fires a press rule for a single detent in the
+            // same direction once per detent.
+            (Some(expected), Mode::Press) if is_wheel(event_code) && expected.abs() == 1 => {
+                expected.signum() == value.signum()
+            }
+            (Some(expected), _) => expected == *value,
+            // A tap never fires on release.
+            (None, Mode::Press) | (None, Mode::Repeat) => *value != 0,
+            (None, Mode::Follow) | (None, Mode::TapHold) => true,
+        };
+        value_matches
+            && self.when_held.iter().all(&is_held)
+            && !self.unless_held.iter().any(&is_held)
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.trigger)?;
+        if let Some(value) = self.value {
+            write!(f, " = {}", value)?;
+        }
+        match self.output {
+            Some(output) => write!(f, " -> {:?} ({:?})", output, self.mode)?,
+            None => {
+                let sequence: Vec<_> = self.sequence.iter().map(ToString::to_string).collect();
+                write!(f, " -> [{}]", sequence.join(", "))?;
+            }
+        }
+        if let Some(hold) = self.hold {
+            write!(f, " or {:?} when held", hold)?;
+        }
+        if let Some(hold_layer) = &self.hold_layer {
+            write!(f, " or layer {} when held", hold_layer)?;
+        }
+        if !self.when_held.is_empty() {
+            let when_held: Vec<_> = self.when_held.iter().map(ToString::to_string).collect();
+            write!(f, " when {} held", when_held.join(", "))?;
+        }
+        if !self.unless_held.is_empty() {
+            let unless_held: Vec<_> = self.unless_held.iter().map(ToString::to_string).collect();
+            write!(f, " unless {} held", unless_held.join(", "))?;
+        }
+        if let Some(device) = &self.device {
+            write!(f, " on {}", device)?;
+        }
+        if let Some(layer) = &self.layer {
+            write!(f, " in layer {}", layer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Whether the device named `source` is grabbed, with its events forwarded through sc2input.
+    pub fn grabs(&self, source: &str) -> bool {
+        source == "keyboard"
+            || self
+                .devices
+                .iter()
+                .any(|device| device.grab && device.name == source)
+    }
+
+    /// The codes the rules can emit, plus REL_X and REL_Y if they include mouse buttons, without
+    /// which the buttons aren't delivered.
+    pub fn output_codes(&self) -> Vec<EventCode> {
+        let mouse_buttons = EV_KEY::BTN_LEFT as u32..=EV_KEY::BTN_TASK as u32;
+        let keys: Vec<_> = self
+            .rules
+            .iter()
+            .flat_map(|rule| {
+                let sequence = rule.sequence.iter().filter_map(|step| match step {
+                    Step::Tap(key) | Step::Press(key) | Step::Release(key) => Some(*key),
+                    Step::DelayMs(_) => None,
+                });
+                rule.output.into_iter().chain(rule.hold).chain(sequence)
+            })
+            .chain(self.chords.iter().map(|chord| chord.output))
+            .collect();
+        let mut codes: Vec<_> = keys.iter().copied().map(EventCode::EV_KEY).collect();
+        if keys
+            .iter()
+            .any(|key| mouse_buttons.contains(&(*key as u32)))
+        {
+            codes.push(EventCode::EV_REL(EV_REL::REL_X));
+            codes.push(EventCode::EV_REL(EV_REL::REL_Y));
+        }
+        codes
+    }
+
+    /// Loads the config at `path`, or the built-in config if `path` is `None` and nothing exists
+    /// at the default location.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = default_path();
+                if !path.exists() {
+                    log::info!("no config at {:?}, using built-in bindings", path);
+                    return Ok(Self::parse(DEFAULT_CONFIG, path).expect("invalid built-in config"));
+                }
+                path
+            }
+        };
+        let contents = std::fs::read_to_string(&path).map_err(|e| Error::Read(path.clone(), e))?;
+        Self::parse(&contents, path)
+    }
+
+    /// Parses and validates `contents`, reporting errors against `path`.
+    pub(crate) fn parse(contents: &str, path: PathBuf) -> Result<Self, Error> {
+        let config: Self = toml::from_str(contents).map_err(|e| Error::Parse(path.clone(), e))?;
+        config.validate().map_err(|e| Error::Invalid(path, e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            if self.layers[..i]
+                .iter()
+                .any(|other| other.name == layer.name)
+            {
+                return Err(format!("layer {:?} is defined more than once", layer.name));
+            }
+            // REL events have no release, which would leave the layer active forever.
+            if layer.mode == LayerMode::Hold && !matches!(layer.activate, EventCode::EV_KEY(_)) {
+                return Err(format!(
+                    "layer {:?} is held, so it must be activated by a key",
+                    layer.name
+                ));
+            }
+        }
+        for rule in self.rules.iter() {
+            for layer in rule.layer.iter().chain(rule.hold_layer.iter()) {
+                if !self.layers.iter().any(|other| &other.name == layer) {
+                    return Err(format!(
+                        "rule {} refers to undefined layer {:?}",
+                        rule, layer
+                    ));
+                }
+            }
+            if rule.output.is_some() != rule.sequence.is_empty() {
+                return Err(format!(
+                    "rule {} must set exactly one of output and sequence",
+                    rule
+                ));
+            }
+            if !rule.sequence.is_empty() && rule.mode != Mode::Press {
+                return Err(format!(
+                    "rule {} has a sequence, so its mode must be press",
+                    rule
+                ));
+            }
+            if !rule.limit.is_empty() && rule.mode != Mode::Press {
+                return Err(format!(
+                    "rule {} has a limit, so its mode must be press",
+                    rule
+                ));
+            }
+            // REL events have no release, which would leave the output held forever, or a tap-hold
+            // waiting forever.
+            if rule.mode == Mode::Follow && !matches!(rule.trigger, EventCode::EV_KEY(_)) {
+                return Err(format!(
+                    "rule {} follows its trigger, so its trigger must be a key",
+                    rule
+                ));
+            }
+            if rule.mode == Mode::TapHold && !matches!(rule.trigger, EventCode::EV_KEY(_)) {
+                return Err(format!(
+                    "rule {} is a tap-hold, so its trigger must be a key",
+                    rule
+                ));
+            }
+            if rule.mode == Mode::Repeat {
+                if !matches!(rule.trigger, EventCode::EV_KEY(_)) {
+                    return Err(format!(
+                        "rule {} repeats, so its trigger must be a key",
+                        rule
+                    ));
+                }
+                if rule.repeat_interval_ms <= rule.repeat_jitter_ms {
+                    return Err(format!(
+                        "rule {} must have a longer repeat_interval_ms than repeat_jitter_ms",
+                        rule
+                    ));
+                }
+            }
+            let holds = rule.hold.is_some() || rule.hold_layer.is_some();
+            if holds != (rule.mode == Mode::TapHold) {
+                return Err(format!(
+                    "rule {} must set hold or hold_layer if and only if its mode is tap_hold",
+                    rule
+                ));
+            }
+        }
+        if self.wheel.max_detents == 0 {
+            return Err("wheel.max_detents must be at least 1".to_string());
+        }
+        if self.wheel.hi_res_threshold <= 0 {
+            return Err("wheel.hi_res_threshold must be positive".to_string());
+        }
+        for chord in self.chords.iter() {
+            if chord.inputs.len() < 2 {
+                return Err(format!("chord {} needs at least 2 inputs", chord));
+            }
+            if chord
+                .inputs
+                .iter()
+                .any(|input| !matches!(input, EventCode::EV_KEY(_)))
+            {
+                return Err(format!(
+                    "chord {} may only have keys and buttons as inputs",
+                    chord
+                ));
+            }
+            if chord.mode != Mode::Press && chord.mode != Mode::Follow {
+                return Err(format!("chord {} must be press or follow", chord));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/sc2remap/config.toml`, falling back to `~/.config` if unset.
+pub fn default_path() -> PathBuf {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            Path::new(&home).join(".config")
+        });
+    config_home.join("sc2remap").join("config.toml")
+}
+
+/// Parses a libevdev code name such as `KEY_END`, `BTN_EXTRA` or `REL_WHEEL`.
+pub fn parse_code(name: &str) -> Option<EventCode> {
+    let event_type = if name.starts_with("KEY_") || name.starts_with("BTN_") {
+        EventType::EV_KEY
+    } else if name.starts_with("REL_") {
+        EventType::EV_REL
+    } else {
+        return None;
+    };
+    EventCode::from_str(&event_type, name)
+}
+
+fn deserialize_code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<EventCode, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    parse_code(&name)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown event code {:?}", name)))
+}
+
+fn deserialize_codes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<EventCode>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|name| {
+            parse_code(&name)
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown event code {:?}", name)))
+        })
+        .collect()
+}
+
+fn deserialize_some_codes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<EventCode>>, D::Error> {
+    deserialize_codes(deserializer).map(Some)
+}
+
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(u16, u16), D::Error> {
+    let id = String::deserialize(deserializer)?;
+    discover::parse_id(&id)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid vendor:product ID {:?}", id)))
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<EV_KEY, D::Error> {
+    match deserialize_code(deserializer)? {
+        EventCode::EV_KEY(key) => Ok(key),
+        code => Err(serde::de::Error::custom(format!("{} is not a key", code))),
+    }
+}
+
+fn deserialize_steps<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Step>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Tap(#[serde(deserialize_with = "deserialize_key")] EV_KEY),
+        Step(Step),
+    }
+    Ok(Vec::<Repr>::deserialize(deserializer)?
+        .into_iter()
+        .map(|repr| match repr {
+            Repr::Tap(key) => Step::Tap(key),
+            Repr::Step(step) => step,
+        })
+        .collect())
+}
+
+fn deserialize_some_key<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<EV_KEY>, D::Error> {
+    deserialize_key(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns why `contents` is an invalid config.
+    fn invalid(contents: &str) -> String {
+        match Config::parse(contents, PathBuf::from("test.toml")) {
+            Err(Error::Invalid(_, e)) => e,
+            Err(e) => panic!("expected an invalid config, got {}", e),
+            Ok(_) => panic!("expected an invalid config"),
+        }
+    }
+
+    #[test]
+    fn built_in_config_is_valid() {
+        let config = Config::parse(DEFAULT_CONFIG, "default_config.toml".into())
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(config.rules.len(), 3);
+    }
+
+    #[test]
+    fn follow_needs_a_key_trigger() {
+        let e = invalid(
+            r#"
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_END"
+            mode = "follow"
+            "#,
+        );
+        assert!(e.contains("must be a key"), "{}", e);
+    }
+
+    #[test]
+    fn tap_hold_needs_a_key_trigger() {
+        let e = invalid(
+            r#"
+            [[rules]]
+            trigger = "REL_HWHEEL"
+            value = 1
+            mode = "tap_hold"
+            output = "KEY_F1"
+            hold = "KEY_LEFTCTRL"
+            "#,
+        );
+        assert!(e.contains("must be a key"), "{}", e);
+    }
+
+    #[test]
+    fn held_layer_needs_a_key() {
+        let e = invalid(
+            r#"
+            [[layers]]
+            name = "scroll"
+            activate = "REL_WHEEL"
+            "#,
+        );
+        assert!(e.contains("activated by a key"), "{}", e);
+    }
+
+    #[test]
+    fn output_codes_cover_every_output() {
+        let config = Config::parse(
+            r#"
+            [[rules]]
+            trigger = "BTN_SIDE"
+            mode = "tap_hold"
+            output = "KEY_F1"
+            hold = "KEY_LEFTCTRL"
+
+            [[rules]]
+            trigger = "BTN_EXTRA"
+            sequence = [{ press = "KEY_LEFTSHIFT" }, { delay_ms = 10 }, "KEY_A"]
+
+            [[chords]]
+            inputs = ["KEY_A", "KEY_S"]
+            output = "KEY_F6"
+            "#,
+            PathBuf::from("test.toml"),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            config.output_codes(),
+            [
+                EventCode::EV_KEY(EV_KEY::KEY_F1),
+                EventCode::EV_KEY(EV_KEY::KEY_LEFTCTRL),
+                EventCode::EV_KEY(EV_KEY::KEY_LEFTSHIFT),
+                EventCode::EV_KEY(EV_KEY::KEY_A),
+                EventCode::EV_KEY(EV_KEY::KEY_F6),
+            ]
+        );
+    }
+
+    #[test]
+    fn mouse_button_outputs_add_rel_motion() {
+        let config = Config::parse(
+            r#"
+            [[rules]]
+            trigger = "KEY_F1"
+            output = "BTN_LEFT"
+            "#,
+            PathBuf::from("test.toml"),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            config.output_codes(),
+            [
+                EventCode::EV_KEY(EV_KEY::BTN_LEFT),
+                EventCode::EV_REL(EV_REL::REL_X),
+                EventCode::EV_REL(EV_REL::REL_Y),
+            ]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::config::Config;
+use crate::discover::{self, Matcher};
+use crate::error::Error;
+use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::{DeviceWrapper as _, GrabMode, InputEvent};
+use evdev_utils::AsyncDevice;
+use futures::stream::{FusedStream, LocalBoxStream, SelectAll};
+use futures::{Stream, StreamExt as _};
+use std::path::Path;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// The name of the device which produced an event, e.g. `mouse`, `keyboard` or the name of an
+/// extra device from the config.
+pub type Source = Rc<str>;
+
+/// An event read from a device, or the error which ended the device's stream.
+pub type TaggedEvent = (Source, std::io::Result<InputEvent>);
+
+/// What identifies a device across reconnects, when its node in `/dev/input` may change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub phys: Option<String>,
+}
+
+impl Identity {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        evdev_rs::Device::new_from_path(path).map(|device| Self::of(&device))
+    }
+
+    /// The identity of a device which is already open.
+    pub fn of(device: &evdev_rs::Device) -> Self {
+        Self {
+            name: device.name().map(str::to_string),
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            phys: device.phys().map(str::to_string),
+        }
+    }
+
+    /// Whether `other` is the same device, possibly plugged into a different port.
+    ///
+    /// Only the interface part of the phys path (e.g. `input0`) is compared, which is enough to
+    /// tell apart the mouse and keyboard interfaces that many gaming mice expose.
+    pub fn matches(&self, other: &Identity) -> bool {
+        fn interface(phys: &Option<String>) -> Option<&str> {
+            phys.as_deref().and_then(|phys| phys.rsplit('/').next())
+        }
+        self.name == other.name
+            && self.vendor_id == other.vendor_id
+            && self.product_id == other.product_id
+            && interface(&self.phys) == interface(&other.phys)
+    }
+}
+
+struct Watched {
+    source: Source,
+    /// Finds the device if its identity was never read.
+    matcher: Matcher,
+    identity: Option<Identity>,
+    grab: bool,
+    /// The KEY and REL codes the device supports, if it is grabbed.
+    codes: Vec<EventCode>,
+    attached: bool,
+}
+
+/// Events from all watched devices, in the order they are read.
+///
+/// Devices whose streams end are remembered, and reattached when a device with the same identity
+/// shows up again.
+#[derive(Default)]
+pub struct Devices {
+    watched: Vec<Watched>,
+    streams: SelectAll<LocalBoxStream<'static, TaggedEvent>>,
+}
+
+impl Devices {
+    /// Starts watching the device at `path` under the name `source`. If there is no such device
+    /// yet or it can't be opened, the first device that appears matching `matcher` is attached.
+    pub fn watch(&mut self, source: &str, matcher: Matcher, path: Option<&Path>, grab: bool) {
+        let mut watched = Watched {
+            source: source.into(),
+            matcher,
+            identity: None,
+            grab,
+            codes: Vec::new(),
+            attached: false,
+        };
+        match path {
+            Some(path) => match attach(&mut self.streams, &mut watched, path) {
+                Ok(()) => log::info!("watching {} at {:?}", source, path),
+                Err(e) => log::warn!("failed to attach {}: {}", source, e),
+            },
+            None => log::warn!("{} not found, waiting for it to appear", source),
+        }
+        self.watched.push(watched);
+    }
+
+    /// Watches the mouse, the keyboard and the extra devices in `config` whose names pass
+    /// `include`. Devices are only grabbed if `grab` is set and the config grabs them. Waiting for
+    /// the mouse or keyboard to be identified stops when `signals` yields a signal.
+    pub fn watch_config<F, S>(
+        &mut self,
+        config: &Config,
+        include: F,
+        grab: bool,
+        signals: &mut S,
+    ) -> Result<(), discover::Error>
+    where
+        F: Fn(&str) -> bool,
+        S: FusedStream<Item = i32> + Unpin,
+    {
+        if include("mouse") {
+            let path = discover::find_or_identify(
+                "mouse",
+                &config.mouse,
+                evdev_utils::identify_mouse,
+                signals,
+            )?;
+            self.watch("mouse", config.mouse.clone(), Some(&path), false);
+        }
+        if include("keyboard") {
+            let path = discover::find_or_identify(
+                "keyboard",
+                &config.keyboard,
+                evdev_utils::identify_keyboard,
+                signals,
+            )?;
+            self.watch("keyboard", config.keyboard.clone(), Some(&path), grab);
+        }
+        for device in config.devices.iter().filter(|device| include(&device.name)) {
+            let path = discover::find(&device.name, &device.matcher)?;
+            self.watch(
+                &device.name,
+                device.matcher.clone(),
+                path.as_deref(),
+                grab && device.grab,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_grabbed(&self, source: &str) -> bool {
+        self.watched
+            .iter()
+            .any(|watched| watched.attached && watched.grab && &*watched.source == source)
+    }
+
+    /// The KEY and REL codes supported by the grabbed devices, whose events are forwarded.
+    pub fn grabbed_codes(&self) -> Vec<EventCode> {
+        self.watched
+            .iter()
+            .flat_map(|watched| watched.codes.iter().copied())
+            .collect()
+    }
+
+    /// Marks `source` as detached after its stream ended.
+    pub fn detach(&mut self, source: &str) {
+        for watched in self.watched.iter_mut().filter(|w| &*w.source == source) {
+            watched.attached = false;
+        }
+    }
+
+    /// Closes every device, which also releases any grabs.
+    pub fn close(&mut self) {
+        self.streams = SelectAll::new();
+        for watched in self.watched.iter_mut() {
+            watched.attached = false;
+        }
+    }
+
+    /// Reattaches any detached device that matches the device node at `path`.
+    pub fn hotplug(&mut self, path: &Path) {
+        let Self { watched, streams } = self;
+        let mut detached = watched
+            .iter_mut()
+            .filter(|watched| !watched.attached)
+            .peekable();
+        if detached.peek().is_none() {
+            return;
+        }
+        let identity = match Identity::read(path) {
+            Ok(identity) => identity,
+            Err(e) => {
+                log::debug!("failed to read identity of {:?}: {}", path, e);
+                return;
+            }
+        };
+        let watched = detached.find(|watched| match &watched.identity {
+            Some(known) => known.matches(&identity),
+            None => !watched.matcher.is_empty() && watched.matcher.matches(path, &identity),
+        });
+        if let Some(watched) = watched {
+            match attach(streams, watched, path) {
+                Ok(()) => log::info!("reattached {} at {:?}", watched.source, path),
+                Err(e) => log::warn!("failed to reattach {}: {}", watched.source, e),
+            }
+        }
+    }
+}
+
+fn attach(
+    streams: &mut SelectAll<LocalBoxStream<'static, TaggedEvent>>,
+    watched: &mut Watched,
+    path: &Path,
+) -> Result<(), Error> {
+    let identity = Identity::read(path).map_err(|e| Error::open(path, e))?;
+    let mut device = AsyncDevice::new(path).map_err(|e| Error::open(path, e))?;
+    if watched.grab {
+        let capabilities =
+            evdev_rs::Device::new_from_path(path).map_err(|e| Error::open(path, e))?;
+        watched.codes = [EventType::EV_KEY, EventType::EV_REL]
+            .iter()
+            .flat_map(|event_type| discover::supported_codes(&capabilities, *event_type))
+            .collect();
+        device
+            .grab(GrabMode::Grab)
+            .map_err(|e| Error::io(format!("failed to grab {:?}", path), e))?;
+    }
+    watched.identity = Some(identity);
+    watched.attached = true;
+    let source = watched.source.clone();
+    // Ends the stream after the first error, which drops the device.
+    let stream = futures::stream::unfold(Some(device), move |device| {
+        let source = source.clone();
+        async move {
+            let mut device = device?;
+            match device.next().await {
+                Some(Ok(event)) => Some(((source, Ok(event)), Some(device))),
+                Some(Err(e)) => Some(((source, Err(e)), None)),
+                None => Some((
+                    (
+                        source,
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "device stream ended",
+                        )),
+                    ),
+                    None,
+                )),
+            }
+        }
+    });
+    streams.push(stream.boxed_local());
+    Ok(())
+}
+
+impl Stream for Devices {
+    type Item = TaggedEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.streams.poll_next_unpin(cx)
+    }
+}
+
+impl FusedStream for Devices {
+    fn is_terminated(&self) -> bool {
+        self.streams.is_terminated()
+    }
+}
@@ -0,0 +1,360 @@
+use crate::devices::Identity;
+use evdev_rs::enums::{EventCode, EventType, EV_KEY, EV_REL};
+use evdev_rs::DeviceWrapper as _;
+use futures::stream::{FusedStream, StreamExt as _};
+use futures::FutureExt as _;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+const BY_ID_DIR: &str = "/dev/input/by-id";
+
+#[derive(Debug)]
+pub enum Error {
+    Ambiguous {
+        device: String,
+        paths: Vec<PathBuf>,
+    },
+    /// A signal arrived while waiting to identify the device.
+    Interrupted {
+        device: String,
+        signal: i32,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Ambiguous { device, paths } => write!(
+                f,
+                "the matcher for {} is ambiguous, it matches {:?}; add more criteria such as phys \
+                 or by_id to select exactly one",
+                device, paths
+            ),
+            Error::Interrupted { device, signal } => {
+                write!(f, "received signal {} while waiting for {}", signal, device)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Selects a device deterministically. Every criterion that is set must match.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Matcher {
+    /// The exact device name.
+    pub name: Option<String>,
+    /// A regex which must match somewhere in the device name.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub name_regex: Option<Regex>,
+    /// The vendor and product IDs in hex, e.g. `046d:c08b`.
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub id: Option<(u16, u16)>,
+    /// The exact phys path, e.g. `usb-0000:00:14.0-2/input0`.
+    pub phys: Option<String>,
+    /// A symlink in `/dev/input/by-id`, either its file name or its full path.
+    pub by_id: Option<PathBuf>,
+    /// A device node or a symlink to one.
+    pub path: Option<PathBuf>,
+}
+
+impl Matcher {
+    pub fn is_empty(&self) -> bool {
+        let Self {
+            name,
+            name_regex,
+            id,
+            phys,
+            by_id,
+            path,
+        } = self;
+        name.is_none()
+            && name_regex.is_none()
+            && id.is_none()
+            && phys.is_none()
+            && by_id.is_none()
+            && path.is_none()
+    }
+
+    pub fn matches(&self, path: &Path, identity: &Identity) -> bool {
+        let Self {
+            name,
+            name_regex,
+            id,
+            phys,
+            by_id,
+            path: expected_path,
+        } = self;
+        let resolves_to =
+            |link: &Path| std::fs::canonicalize(link).ok() == std::fs::canonicalize(path).ok();
+        name.as_ref()
+            .is_none_or(|name| identity.name.as_ref() == Some(name))
+            && name_regex.as_ref().is_none_or(|regex| {
+                identity
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| regex.is_match(name))
+            })
+            && id.is_none_or(|id| id == (identity.vendor_id, identity.product_id))
+            && phys
+                .as_ref()
+                .is_none_or(|phys| identity.phys.as_ref() == Some(phys))
+            && by_id
+                .as_ref()
+                .is_none_or(|by_id| resolves_to(&Path::new(BY_ID_DIR).join(by_id)))
+            && expected_path.as_ref().is_none_or(|p| resolves_to(p))
+    }
+}
+
+/// Finds the device selected by `matcher`, falling back to identifying it with `identify` from the
+/// events it generates if the matcher is empty or matches nothing. Waiting stops with
+/// [`Error::Interrupted`] when `signals` yields a signal.
+pub fn find_or_identify<F, S>(
+    device: &str,
+    matcher: &Matcher,
+    identify: fn() -> F,
+    signals: &mut S,
+) -> Result<PathBuf, Error>
+where
+    F: Future<Output = std::io::Result<PathBuf>>,
+    S: FusedStream<Item = i32> + Unpin,
+{
+    if !matcher.is_empty() {
+        match find(device, matcher)? {
+            Some(path) => {
+                log::info!("found {} {:?} by matcher", device, path);
+                return Ok(path);
+            }
+            None => log::warn!(
+                "no device matches the {} matcher, identifying it instead",
+                device
+            ),
+        }
+    }
+    let path = loop {
+        log::info!("waiting for {}", device);
+        let identified = futures::executor::block_on(async {
+            futures::select! {
+                identified = Box::pin(identify().fuse()) => Ok(identified),
+                signal = signals.select_next_some() => Err(signal),
+            }
+        });
+        let identified = identified.map_err(|signal| Error::Interrupted {
+            device: device.to_owned(),
+            signal,
+        })?;
+        match identified {
+            Ok(path) => break path,
+            Err(e) => log::warn!("failed to identify {}: {}", device, e),
+        }
+    };
+    log::info!("found {} {:?}", device, path);
+    Ok(path)
+}
+
+/// Returns the paths of all evdev device nodes.
+pub fn event_devices() -> Vec<PathBuf> {
+    let mut paths: Vec<_> = glob::glob("/dev/input/event*")
+        .expect("invalid glob pattern")
+        .filter_map(Result::ok)
+        .collect();
+    // Sort numerically so that event10 comes after event9.
+    paths.sort_by_key(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.trim_start_matches("event").parse::<u32>().ok())
+    });
+    paths
+}
+
+/// Finds the single device matching `matcher`, or `None` if no device does.
+pub fn find(device: &str, matcher: &Matcher) -> Result<Option<PathBuf>, Error> {
+    let mut paths: Vec<_> = event_devices()
+        .into_iter()
+        .filter(|path| match Identity::read(path) {
+            Ok(identity) => matcher.matches(path, &identity),
+            Err(e) => {
+                log::debug!("failed to read identity of {:?}: {}", path, e);
+                false
+            }
+        })
+        .collect();
+    match paths.len() {
+        0 => Ok(None),
+        1 => Ok(paths.pop()),
+        _ => Err(Error::Ambiguous {
+            device: device.to_string(),
+            paths,
+        }),
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let regex = String::deserialize(deserializer)?;
+    Regex::new(&regex)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<(u16, u16)>, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    parse_id(&id)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid vendor:product ID {:?}", id)))
+}
+
+/// Parses vendor and product IDs in hex, e.g. `046d:c08b`.
+pub fn parse_id(id: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = id.split_once(':')?;
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(product, 16).ok()?,
+    ))
+}
+
+const MOUSE_BUTTONS: [EV_KEY; 5] = [
+    EV_KEY::BTN_LEFT,
+    EV_KEY::BTN_RIGHT,
+    EV_KEY::BTN_MIDDLE,
+    EV_KEY::BTN_SIDE,
+    EV_KEY::BTN_EXTRA,
+];
+
+/// How sc2remap's heuristic would classify a device, judging by its capabilities rather than the
+/// events it generates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Mouse,
+    Keyboard,
+    /// Both, e.g. a gaming mouse with a single interface for its buttons and macro keys.
+    Both,
+    Neither,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Kind::Mouse => "mouse",
+            Kind::Keyboard => "keyboard",
+            Kind::Both => "mouse and keyboard",
+            Kind::Neither => "neither mouse nor keyboard",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// A mouse generates the 5 main buttons, the scroll wheel and REL events; a keyboard generates any
+/// KEY event that is not one of those.
+pub fn classify(device: &evdev_rs::Device) -> Kind {
+    let mouse = MOUSE_BUTTONS
+        .iter()
+        .all(|button| device.has(EventCode::EV_KEY(*button)))
+        && device.has(EventCode::EV_REL(EV_REL::REL_WHEEL))
+        && device.has(EventCode::EV_REL(EV_REL::REL_X))
+        && device.has(EventCode::EV_REL(EV_REL::REL_Y));
+    let keyboard = supported_codes(device, EventType::EV_KEY)
+        .iter()
+        .any(|code| match code {
+            EventCode::EV_KEY(key) => !MOUSE_BUTTONS.contains(key),
+            _ => false,
+        });
+    match (mouse, keyboard) {
+        (true, true) => Kind::Both,
+        (true, false) => Kind::Mouse,
+        (false, true) => Kind::Keyboard,
+        (false, false) => Kind::Neither,
+    }
+}
+
+/// Returns every code of `event_type` that `device` supports.
+pub fn supported_codes(device: &evdev_rs::Device, event_type: EventType) -> Vec<EventCode> {
+    if !device.has(event_type) {
+        return Vec::new();
+    }
+    let max = evdev_rs::util::event_type_get_max(&event_type).unwrap_or(0);
+    (0..=max)
+        .map(|code| evdev_rs::util::int_to_event_code(event_type as u32, code))
+        .filter(|code| !matches!(code, EventCode::EV_UNK { .. }) && device.has(*code))
+        .collect()
+}
+
+/// Returns the names of the symlinks in `/dev/input/by-id` which point to `path`.
+pub fn by_id_links(path: &Path) -> Vec<String> {
+    let canonical = match std::fs::canonicalize(path) {
+        Ok(canonical) => canonical,
+        Err(_) => return Vec::new(),
+    };
+    let entries = match std::fs::read_dir(BY_ID_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut links: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| std::fs::canonicalize(entry.path()).ok().as_ref() == Some(&canonical))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    links.sort();
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity {
+            name: Some("Logitech G Pro".to_string()),
+            vendor_id: 0x046d,
+            product_id: 0xc08b,
+            phys: Some("usb-0000:00:14.0-2/input0".to_string()),
+        }
+    }
+
+    fn matches(matcher: &str) -> bool {
+        let matcher: Matcher = toml::from_str(matcher).expect("invalid matcher");
+        matcher.matches(Path::new("/dev/input/event0"), &identity())
+    }
+
+    #[test]
+    fn parse_id_reads_hex_pairs() {
+        assert_eq!(parse_id("046d:c08b"), Some((0x046d, 0xc08b)));
+        assert_eq!(parse_id("1:A"), Some((1, 10)));
+        assert_eq!(parse_id("046dc08b"), None);
+        assert_eq!(parse_id("046d:"), None);
+        assert_eq!(parse_id("046d:c08g"), None);
+        assert_eq!(parse_id("10000:0001"), None);
+        assert_eq!(parse_id("0001:0002:0003"), None);
+    }
+
+    #[test]
+    fn matcher_checks_every_criterion() {
+        assert!(matches(""));
+        assert!(matches(r#"name = "Logitech G Pro""#));
+        assert!(!matches(r#"name = "Logitech""#));
+        assert!(matches(r#"name_regex = "^Logitech""#));
+        assert!(!matches(r#"name_regex = "^Razer""#));
+        assert!(matches(r#"id = "046d:c08b""#));
+        assert!(!matches(r#"id = "046d:c08c""#));
+        assert!(matches(r#"phys = "usb-0000:00:14.0-2/input0""#));
+        assert!(!matches(r#"phys = "usb-0000:00:14.0-2/input1""#));
+        assert!(matches(
+            r#"
+            name_regex = "G Pro$"
+            id = "046d:c08b"
+            "#
+        ));
+        assert!(!matches(
+            r#"
+            name = "Logitech G Pro"
+            id = "046d:0000"
+            "#
+        ));
+    }
+}
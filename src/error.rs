@@ -0,0 +1,140 @@
+use crate::{config, discover};
+use std::path::{Path, PathBuf};
+
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+#[derive(Debug)]
+pub enum Error {
+    Config(config::Error),
+    Discover(discover::Error),
+    /// A device node or `/dev/uinput` can't be opened by this user.
+    PermissionDenied {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// `/dev/uinput` doesn't exist, usually because the uinput module isn't loaded.
+    UinputMissing(std::io::Error),
+    /// The device was unplugged.
+    DeviceGone {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Writing to sc2input failed even after retrying.
+    Write(std::io::Error),
+    /// Another instance holds the pid file.
+    AlreadyRunning {
+        pid_file: PathBuf,
+    },
+    /// The pid file can't be created, e.g. because its directory doesn't exist.
+    PidFile {
+        pid_file: PathBuf,
+    },
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// Some of the `doctor` checks failed.
+    ChecksFailed(usize),
+}
+
+impl Error {
+    /// Classifies a failure to open `path`.
+    pub fn open(path: &Path, source: std::io::Error) -> Self {
+        let path = path.to_path_buf();
+        if source.kind() == std::io::ErrorKind::PermissionDenied {
+            Error::PermissionDenied { path, source }
+        } else if is_device_gone(&source) {
+            Error::DeviceGone { path, source }
+        } else if source.kind() == std::io::ErrorKind::NotFound && path == Path::new(UINPUT_PATH) {
+            Error::UinputMissing(source)
+        } else {
+            Error::Io {
+                context: format!("failed to open {:?}", path),
+                source,
+            }
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+/// Whether `e` means the device went away, e.g. because it was unplugged.
+pub fn is_device_gone(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENODEV)
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Config(e) => e.fmt(f),
+            Error::Discover(e) => e.fmt(f),
+            Error::PermissionDenied { path, source } if path == Path::new(UINPUT_PATH) => write!(
+                f,
+                "cannot open {}: {}; allow the input group to write it with a udev rule such as \
+                 KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\", and add yourself to the \
+                 input group",
+                UINPUT_PATH, source
+            ),
+            Error::PermissionDenied { path, source } => write!(
+                f,
+                "cannot open {:?}: {}; add yourself to the input group with `sudo usermod -aG \
+                 input $USER` and log in again",
+                path, source
+            ),
+            Error::UinputMissing(source) => write!(
+                f,
+                "cannot open {}: {}; load the uinput module with `sudo modprobe uinput`",
+                UINPUT_PATH, source
+            ),
+            Error::DeviceGone { path, source } => {
+                write!(f, "device {:?} is gone: {}", path, source)
+            }
+            Error::Write(source) => write!(f, "failed to write to sc2input: {}", source),
+            Error::AlreadyRunning { pid_file } => write!(
+                f,
+                "another instance is already running, according to {:?}; pass a different \
+                 --instance to run another",
+                pid_file
+            ),
+            Error::PidFile { pid_file } => write!(
+                f,
+                "cannot create pid file {:?}; check that its directory exists and is writable",
+                pid_file
+            ),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::ChecksFailed(failures) => write!(f, "{} checks failed", failures),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e) => Some(e),
+            Error::Discover(e) => Some(e),
+            Error::PermissionDenied { source, .. }
+            | Error::UinputMissing(source)
+            | Error::DeviceGone { source, .. }
+            | Error::Write(source)
+            | Error::Io { source, .. } => Some(source),
+            Error::AlreadyRunning { .. } | Error::PidFile { .. } | Error::ChecksFailed(_) => None,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<discover::Error> for Error {
+    fn from(e: discover::Error) -> Self {
+        Error::Discover(e)
+    }
+}
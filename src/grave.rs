@@ -0,0 +1,59 @@
+use evdev_rs::enums::{EventCode, EV_KEY};
+use evdev_rs::InputEvent;
+
+const MODIFIERS: [EV_KEY; 8] = [
+    EV_KEY::KEY_LEFTSHIFT,
+    EV_KEY::KEY_RIGHTSHIFT,
+    EV_KEY::KEY_LEFTCTRL,
+    EV_KEY::KEY_RIGHTCTRL,
+    EV_KEY::KEY_LEFTALT,
+    EV_KEY::KEY_RIGHTALT,
+    EV_KEY::KEY_LEFTMETA,
+    EV_KEY::KEY_RIGHTMETA,
+];
+
+/// Redefines the grave key as the most recently pressed non-modifier key.
+#[derive(Default)]
+pub struct Grave {
+    /// The most recently pressed non-modifier key other than grave.
+    last: Option<EV_KEY>,
+    /// The key emitted for the current grave press, so that the release matches it even if `last`
+    /// changed in the meantime.
+    pressed: Option<EV_KEY>,
+}
+
+impl Grave {
+    /// Returns the keyboard event to forward in place of `event`.
+    pub fn map(&mut self, event: InputEvent) -> InputEvent {
+        let InputEvent {
+            time,
+            event_code,
+            value,
+        } = event;
+        match event_code {
+            EventCode::EV_KEY(EV_KEY::KEY_GRAVE) => {
+                let key = match value {
+                    1 => {
+                        let key = self.last.unwrap_or(EV_KEY::KEY_GRAVE);
+                        self.pressed = Some(key);
+                        key
+                    }
+                    0 => self.pressed.take().unwrap_or(EV_KEY::KEY_GRAVE),
+                    _ => self.pressed.unwrap_or(EV_KEY::KEY_GRAVE),
+                };
+                InputEvent {
+                    time,
+                    event_code: EventCode::EV_KEY(key),
+                    value,
+                }
+            }
+            EventCode::EV_KEY(key) => {
+                if value == 1 && !MODIFIERS.contains(&key) {
+                    self.last = Some(key);
+                }
+                event
+            }
+            _ => event,
+        }
+    }
+}
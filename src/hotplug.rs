@@ -0,0 +1,41 @@
+use futures::channel::mpsc;
+use inotify::{Inotify, WatchMask};
+use std::path::{Path, PathBuf};
+
+pub const INPUT_DIR: &str = "/dev/input";
+
+/// Watches `/dev/input` for new event device nodes, sending their paths.
+///
+/// A path may be sent more than once: udev typically fixes up permissions on the node after
+/// creating it, so the node may not be readable yet when it first appears.
+pub fn watch() -> std::io::Result<mpsc::UnboundedReceiver<PathBuf>> {
+    let mut inotify = Inotify::init()?;
+    let _: inotify::WatchDescriptor =
+        inotify.add_watch(INPUT_DIR, WatchMask::CREATE | WatchMask::ATTRIB)?;
+    let (sender, receiver) = mpsc::unbounded();
+    let _: std::thread::JoinHandle<()> = std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    log::error!("failed to watch {}: {}", INPUT_DIR, e);
+                    return;
+                }
+            };
+            for event in events {
+                let name = match event.name.and_then(|name| name.to_str()) {
+                    Some(name) if name.starts_with("event") => name,
+                    _ => continue,
+                };
+                if sender
+                    .unbounded_send(Path::new(INPUT_DIR).join(name))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
@@ -0,0 +1,16 @@
+//! The remapping engine behind sc2remap: the rule config, device discovery and hotplugging, the
+//! remapper itself and the uinput device it writes to.
+
+#![deny(unused_results)]
+
+pub mod config;
+pub mod devices;
+pub mod discover;
+pub mod error;
+pub mod grave;
+pub mod hotplug;
+pub mod output;
+pub mod pidfile;
+pub mod recording;
+pub mod remap;
+pub mod signals;
@@ -1,12 +1,18 @@
 #![deny(unused_results)]
 
+mod commands;
+
 use argh::FromArgs;
-use evdev_rs::enums::{EventCode, EV_KEY, EV_REL};
-use evdev_rs::{DeviceWrapper as _, InputEvent, UInputDevice};
-use evdev_utils::AsyncDevice;
-use evdev_utils::{DeviceWrapperExt as _, UInputExt as _};
-use futures::TryStreamExt as _;
+use evdev_rs::enums::{EventCode, EV_REL};
+use evdev_rs::{InputEvent, UInputDevice};
+use futures::channel::mpsc;
+use futures::{FutureExt as _, StreamExt as _};
 use log::{debug, info, trace};
+use sc2remap::config::{Config, Uinput};
+use sc2remap::error::Error;
+use sc2remap::output::Output;
+use sc2remap::{devices, discover, hotplug, output, pidfile, remap, signals};
+use std::path::{Path, PathBuf};
 
 #[derive(FromArgs)]
 /// SC2 input remapping arguments.
@@ -14,6 +20,25 @@ struct Args {
     /// log level
     #[argh(option, short = 'l', default = "log::LevelFilter::Info")]
     log_level: log::LevelFilter,
+
+    /// path to the bindings config, defaults to $XDG_CONFIG_HOME/sc2remap/config.toml
+    #[argh(option, short = 'c')]
+    config: Option<PathBuf>,
+
+    /// name of this instance, to run several at once, e.g. with one config per mouse
+    #[argh(option, short = 'i', from_str_fn(parse_instance))]
+    instance: Option<String>,
+
+    #[argh(subcommand)]
+    command: Option<commands::Command>,
+}
+
+fn parse_instance(instance: &str) -> Result<String, String> {
+    if instance.is_empty() || instance.contains('/') {
+        Err(format!("invalid instance name {:?}", instance))
+    } else {
+        Ok(instance.to_string())
+    }
 }
 
 fn log_event(event: &InputEvent) {
@@ -25,8 +50,62 @@ fn log_event(event: &InputEvent) {
     }
 }
 
+/// Writes `outputs` to sc2input, recreating it if it went away. Outputs which still can't be
+/// written are dropped rather than ending the session.
+fn write_outputs(
+    l: &mut output::HeldKeys<UInputDevice>,
+    uinput: &Uinput,
+    codes: &[EventCode],
+    outputs: &[Output],
+) {
+    let e = match output::write_retrying(l, outputs) {
+        Ok(()) => return,
+        Err(Error::DeviceGone { .. }) => {
+            log::error!("sc2input is gone, recreating it");
+            match output::create_uinput(uinput, codes) {
+                Ok(device) => l.replace(device),
+                Err(e) => return log::error!("{}", e),
+            }
+            match output::write_retrying(l, outputs) {
+                Ok(()) => return,
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    };
+    log::error!("{}, dropping {:?}", e, outputs);
+}
+
+/// The codes to enable on sc2input: those for the rules, and those of the grabbed devices, whose
+/// events are forwarded.
+fn uinput_codes(config: &Config, devices: &devices::Devices) -> Vec<EventCode> {
+    let mut codes = config
+        .uinput
+        .codes
+        .clone()
+        .unwrap_or_else(|| config.output_codes());
+    codes.extend(devices.grabbed_codes());
+    codes
+}
+
+/// Replaces sc2input with a device with `codes` enabled, releasing the keys held on the old one.
+fn recreate_uinput(l: &mut output::HeldKeys<UInputDevice>, uinput: &Uinput, codes: &[EventCode]) {
+    if let Err(e) = l.release_all() {
+        log::error!("failed to release held keys: {}", e);
+    }
+    match output::create_uinput(uinput, codes) {
+        Ok(device) => l.replace(device),
+        Err(e) => log::error!("{}", e),
+    }
+}
+
 fn main() {
-    let Args { log_level } = argh::from_env();
+    let Args {
+        log_level,
+        config,
+        instance,
+        command,
+    } = argh::from_env();
 
     simple_logger::SimpleLogger::new()
         .with_utc_timestamps()
@@ -35,71 +114,112 @@ fn main() {
         .init()
         .expect("failed to initialize logger");
 
-    let mut pidlock = pidlock::Pidlock::new(&format!("/var/run/user/{}/sc2remap.pid", unsafe {
-        libc::geteuid()
-    }));
-    pidlock.acquire().unwrap();
-
-    loop {
-        let mouse_path = loop {
-            log::info!("waiting");
-            match futures::executor::block_on(evdev_utils::identify_mouse()) {
-                Ok(mouse_path) => break mouse_path,
-                Err(e) => log::warn!("failed to identify mouse: {}", e),
-            }
-        };
-        info!("found mouse {:?}", mouse_path);
-
-        let uninit_device = evdev_rs::UninitDevice::new().expect("failed to create uninit device");
-        uninit_device
-            .enable_keys()
-            .expect("failed to enable keyboard functionality");
-        uninit_device.set_name("sc2input");
-        uninit_device.set_product_id(1);
-        uninit_device.set_vendor_id(1);
-        uninit_device.set_bustype(3);
-        let l =
-            UInputDevice::create_from_device(&uninit_device).expect("failed to create uinput device");
-
-        let mouse_device = AsyncDevice::new(mouse_path).expect("failed to create mouse device");
-
-        let mut drag_scroll_held = false;
-        let r = futures::executor::block_on(mouse_device.try_for_each(|mouse_event| {
-            log_event(&mouse_event);
-            let InputEvent {
-                time: _,
-                event_code,
-                value,
-            } = mouse_event;
-            match event_code {
-                // middle click
-                EventCode::EV_KEY(EV_KEY::BTN_MIDDLE) => {
-                    drag_scroll_held = value == 1;
-                }
-                // scroll up
-                EventCode::EV_REL(EV_REL::REL_WHEEL) if value == 1 => {
-                    if !drag_scroll_held {
-                        debug!("injecting UP");
-                        l.inject_key_press(EV_KEY::KEY_END)
-                            .expect("failed to inject up on scrollup");
+    let r = match command {
+        Some(command) => command.run(config.as_deref(), instance.as_deref()),
+        None => remap(config.as_deref(), instance.as_deref()),
+    };
+    if let Err(e) = r {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn remap(config: Option<&Path>, instance: Option<&str>) -> Result<(), Error> {
+    // Load the config first so that a bad one is reported without touching the pid file.
+    let config = Config::load(config)?;
+    info!("loaded {} rules", config.rules.len());
+
+    let pid_file = pidfile::path(instance);
+    pidfile::remove_stale(&pid_file)
+        .map_err(|e| Error::io(format!("failed to remove stale pid file {:?}", pid_file), e))?;
+    // Handle signals before anything can block, so that they don't leave the pid file behind.
+    let mut signals = signals::watch().map_err(|e| Error::io("failed to handle signals", e))?;
+    let mut pidlock = pidlock::Pidlock::new(&pid_file.to_string_lossy());
+    pidlock.acquire().map_err(|_| {
+        let pid_file = pid_file.clone();
+        if pid_file.exists() {
+            Error::AlreadyRunning { pid_file }
+        } else {
+            Error::PidFile { pid_file }
+        }
+    })?;
+
+    let result = remap_locked(&config, &mut signals);
+    if let Err(e) = pidlock.release() {
+        log::warn!("failed to release pid file {:?}: {:?}", pid_file, e);
+    }
+    result
+}
+
+/// Remaps until a signal arrives. The caller holds the pid lock, and releases it however this
+/// returns.
+fn remap_locked(config: &Config, signals: &mut mpsc::UnboundedReceiver<i32>) -> Result<(), Error> {
+    let mut hotplug =
+        hotplug::watch().map_err(|e| Error::io("failed to watch for new devices", e))?;
+
+    let mut devices = devices::Devices::default();
+    match devices.watch_config(config, |_| true, true, signals) {
+        Err(discover::Error::Interrupted { signal, .. }) => {
+            info!("received signal {}, exiting", signal);
+            return Ok(());
+        }
+        result => result?,
+    }
+
+    let mut codes = uinput_codes(config, &devices);
+    let mut l = output::HeldKeys::new(output::create_uinput(&config.uinput, &codes)?);
+
+    let mut remapper = remap::Remapper::new(config);
+    futures::executor::block_on(async {
+        loop {
+            let deadline = remapper.next_deadline();
+            let timeout = async {
+                match deadline {
+                    Some(deadline) => {
+                        futures_timer::Delay::new(deadline.saturating_sub(remap::now())).await
                     }
+                    None => futures::future::pending().await,
+                }
+            };
+            let (source, event) = futures::select! {
+                tagged = devices.select_next_some() => tagged,
+                () = timeout.fuse() => {
+                    write_outputs(&mut l, &config.uinput, &codes, &remapper.tick(remap::now()));
+                    continue;
                 }
-                // scroll down
-                EventCode::EV_REL(EV_REL::REL_WHEEL) if value == -1 => {
-                    if !drag_scroll_held {
-                        debug!("injecting DOWN");
-                        l.inject_key_press(EV_KEY::KEY_PAGEDOWN)
-                            .expect("failed to inject down on scrolldown");
+                path = hotplug.select_next_some() => {
+                    devices.hotplug(&path);
+                    let wanted = uinput_codes(config, &devices);
+                    if wanted.iter().any(|code| !codes.contains(code)) {
+                        info!("recreating sc2input with the codes of a newly attached device");
+                        codes = wanted;
+                        recreate_uinput(&mut l, &config.uinput, &codes);
                     }
+                    continue;
                 }
-                EventCode::EV_KEY(EV_KEY::BTN_EXTRA) => {
-                    l.inject_key_syn(EV_KEY::KEY_DELETE, value)
-                        .expect("failed to inject delete on forward side button");
+                signal = signals.select_next_some() => {
+                    info!("received signal {}, exiting", signal);
+                    break;
                 }
-                _ => {}
-            }
-            futures::future::ok(())
-        }));
-        log::warn!("mouse event loop ended with: {:?}", r);
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("{} event loop ended with: {:?}", source, e);
+                    devices.detach(&source);
+                    write_outputs(&mut l, &config.uinput, &codes, &remapper.detach(&source));
+                    continue;
+                }
+            };
+            log_event(&event);
+            let outputs = remapper.process(&source, devices.is_grabbed(&source), event);
+            write_outputs(&mut l, &config.uinput, &codes, &outputs);
+        }
+    });
+
+    if let Err(e) = l.release_all() {
+        log::error!("failed to release held keys: {}", e);
     }
+    devices.close();
+    Ok(())
 }
@@ -0,0 +1,229 @@
+use crate::config::Uinput;
+use crate::error::{is_device_gone, Error, UINPUT_PATH};
+use evdev_rs::enums::{EventCode, EV_KEY};
+use evdev_rs::{DeviceWrapper as _, InputEvent, UInputDevice};
+use evdev_utils::UInputExt as _;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// How many times a write to sc2input is attempted before the output is dropped.
+const WRITE_ATTEMPTS: usize = 3;
+
+/// Creates the sc2input uinput device, which every output is written to, with the identity in
+/// `uinput` and `codes` enabled.
+pub fn create_uinput(uinput: &Uinput, codes: &[EventCode]) -> Result<UInputDevice, Error> {
+    let uninit_device = evdev_rs::UninitDevice::new().ok_or_else(|| {
+        Error::io(
+            "failed to create uinput device",
+            std::io::Error::other("libevdev_new failed"),
+        )
+    })?;
+    for code in codes {
+        uninit_device
+            .enable(*code)
+            .map_err(|e| Error::io(format!("failed to enable {}", code), e))?;
+    }
+    let Uinput {
+        name,
+        id: (vendor_id, product_id),
+        bustype,
+        codes: _,
+    } = uinput;
+    uninit_device.set_name(name);
+    uninit_device.set_product_id(*product_id);
+    uninit_device.set_vendor_id(*vendor_id);
+    uninit_device.set_bustype(*bustype);
+    UInputDevice::create_from_device(&uninit_device)
+        .map_err(|e| Error::open(Path::new(UINPUT_PATH), e))
+}
+
+/// Writes `outputs` to `sink`, retrying failed writes. Gives up on an output with
+/// `Error::DeviceGone` if sink is gone, or `Error::Write` if it keeps failing, without writing the
+/// remaining outputs.
+pub fn write_retrying<S: EventSink>(sink: &mut S, outputs: &[Output]) -> Result<(), Error> {
+    for output in outputs {
+        let mut attempt = 1;
+        while let Err(e) = sink.write(output) {
+            if is_device_gone(&e) {
+                return Err(Error::DeviceGone {
+                    path: PathBuf::from(UINPUT_PATH),
+                    source: e,
+                });
+            }
+            if attempt == WRITE_ATTEMPTS {
+                return Err(Error::Write(e));
+            }
+            log::debug!("retrying write of {} after: {}", output, e);
+            attempt += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Something for the sink to write to sc2input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// An event from a grabbed device, possibly modified.
+    Forward(InputEvent),
+    /// A press and release of a key.
+    Press(EV_KEY),
+    /// A key press (1), release (0) or repeat (2).
+    Key(EV_KEY, i32),
+}
+
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Output::Forward(event) => write!(f, "forward {} {}", event.event_code, event.value),
+            Output::Press(key) => write!(f, "press {:?}", key),
+            Output::Key(key, value) => write!(f, "key {:?} {}", key, value),
+        }
+    }
+}
+
+/// Where the remapper's output goes.
+pub trait EventSink {
+    fn write(&mut self, output: &Output) -> std::io::Result<()>;
+
+    fn write_all(&mut self, outputs: &[Output]) -> std::io::Result<()> {
+        outputs.iter().try_for_each(|output| self.write(output))
+    }
+}
+
+impl EventSink for UInputDevice {
+    fn write(&mut self, output: &Output) -> std::io::Result<()> {
+        match output {
+            Output::Forward(event) => self.write_event(event),
+            Output::Press(key) => self.inject_key_press(*key),
+            Output::Key(key, value) => self.inject_key_syn(*key, *value),
+        }
+    }
+}
+
+/// Collects outputs in memory.
+impl EventSink for Vec<Output> {
+    fn write(&mut self, output: &Output) -> std::io::Result<()> {
+        self.push(*output);
+        Ok(())
+    }
+}
+
+/// Remembers which keys are held down on the wrapped sink, so that they can be released before
+/// exiting rather than staying pressed until the uinput device is destroyed.
+pub struct HeldKeys<S> {
+    sink: S,
+    held: HashSet<EV_KEY>,
+}
+
+impl<S: EventSink> HeldKeys<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            held: HashSet::new(),
+        }
+    }
+
+    /// Replaces the wrapped sink, e.g. after recreating the uinput device. The keys held on the
+    /// old sink are forgotten, since they went away with it.
+    pub fn replace(&mut self, sink: S) {
+        self.sink = sink;
+        self.held.clear();
+    }
+
+    /// Releases every held key.
+    pub fn release_all(&mut self) -> std::io::Result<()> {
+        let Self { sink, held } = self;
+        for key in held.drain() {
+            log::info!("releasing held {:?}", key);
+            sink.write(&Output::Key(key, 0))?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: EventSink> EventSink for HeldKeys<S> {
+    fn write(&mut self, output: &Output) -> std::io::Result<()> {
+        let Self { sink, held } = self;
+        sink.write(output)?;
+        let (key, value) = match output {
+            Output::Forward(InputEvent {
+                time: _,
+                event_code: EventCode::EV_KEY(key),
+                value,
+            }) => (key, value),
+            Output::Key(key, value) => (key, value),
+            Output::Forward(_) | Output::Press(_) => return Ok(()),
+        };
+        if *value == 0 {
+            let _: bool = held.remove(key);
+        } else {
+            let _: bool = held.insert(*key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails the first `failures` writes with `errno`, then collects the outputs.
+    struct FailingSink {
+        failures: usize,
+        errno: i32,
+        attempts: usize,
+        written: Vec<Output>,
+    }
+
+    impl FailingSink {
+        fn new(failures: usize, errno: i32) -> Self {
+            Self {
+                failures,
+                errno,
+                attempts: 0,
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl EventSink for FailingSink {
+        fn write(&mut self, output: &Output) -> std::io::Result<()> {
+            self.attempts += 1;
+            if self.attempts <= self.failures {
+                return Err(std::io::Error::from_raw_os_error(self.errno));
+            }
+            self.written.push(*output);
+            Ok(())
+        }
+    }
+
+    const OUTPUTS: &[Output] = &[Output::Press(EV_KEY::KEY_A), Output::Press(EV_KEY::KEY_B)];
+
+    #[test]
+    fn retries_failed_writes() {
+        let mut sink = FailingSink::new(WRITE_ATTEMPTS - 1, libc::EIO);
+        write_retrying(&mut sink, OUTPUTS).unwrap();
+        assert_eq!(sink.attempts, WRITE_ATTEMPTS + 1);
+        assert_eq!(sink.written, OUTPUTS);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let mut sink = FailingSink::new(usize::MAX, libc::EIO);
+        let result = write_retrying(&mut sink, OUTPUTS);
+        assert!(matches!(result, Err(Error::Write(_))), "{:?}", result);
+        assert_eq!(sink.attempts, WRITE_ATTEMPTS);
+        assert_eq!(sink.written, []);
+    }
+
+    #[test]
+    fn does_not_retry_a_gone_device() {
+        let mut sink = FailingSink::new(usize::MAX, libc::ENODEV);
+        let result = write_retrying(&mut sink, OUTPUTS);
+        match result {
+            Err(Error::DeviceGone { path, .. }) => assert_eq!(path, Path::new(UINPUT_PATH)),
+            result => panic!("expected DeviceGone, got {:?}", result),
+        }
+        assert_eq!(sink.attempts, 1);
+    }
+}
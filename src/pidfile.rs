@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+/// The directory holding pid files: `$XDG_RUNTIME_DIR`, falling back to `/var/run/user/<euid>`.
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(format!("/var/run/user/{}", unsafe { libc::geteuid() })),
+    }
+}
+
+/// The pid file which keeps more than one sc2remap from running as `instance` at once.
+pub fn path(instance: Option<&str>) -> PathBuf {
+    runtime_dir().join(match instance {
+        Some(instance) => format!("sc2remap-{}.pid", instance),
+        None => "sc2remap.pid".to_string(),
+    })
+}
+
+/// Returns the pid recorded in `pid_file`, if it belongs to a running sc2remap.
+///
+/// A pid file left behind by a crash may record a pid which has since been reused by an unrelated
+/// process, so the process name is compared with this one's.
+pub fn owner(pid_file: &Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(pid_file)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let comm = |pid: &str| std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok();
+    let name = comm(&pid.to_string())?;
+    if Some(name) == comm("self") {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+/// Removes `pid_file` if it is stale, i.e. exists but doesn't belong to a running sc2remap.
+pub fn remove_stale(pid_file: &Path) -> std::io::Result<()> {
+    if !pid_file.exists() || owner(pid_file).is_some() {
+        return Ok(());
+    }
+    log::warn!("removing stale pid file {:?}", pid_file);
+    std::fs::remove_file(pid_file)
+}
@@ -0,0 +1,113 @@
+use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::{InputEvent, TimeVal};
+use serde::{Deserialize, Serialize};
+
+/// An event as stored in a recording, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    /// The name of the device the event came from, e.g. `mouse`.
+    pub source: String,
+    pub sec: libc::time_t,
+    pub usec: libc::suseconds_t,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub code: String,
+    pub value: i32,
+}
+
+impl Record {
+    /// Returns `None` for events whose code has no name, which can't be recorded.
+    pub fn new(source: &str, event: &InputEvent) -> Option<Self> {
+        let InputEvent {
+            time,
+            event_code,
+            value,
+        } = event;
+        let event_type = event.event_type()?;
+        if let EventCode::EV_UNK { .. } = event_code {
+            return None;
+        }
+        Some(Self {
+            source: source.to_string(),
+            sec: time.tv_sec,
+            usec: time.tv_usec,
+            event_type: event_type.to_string(),
+            code: event_code.to_string(),
+            value: *value,
+        })
+    }
+
+    pub fn event(&self) -> Option<InputEvent> {
+        let Self {
+            source: _,
+            sec,
+            usec,
+            event_type,
+            code,
+            value,
+        } = self;
+        let event_type = EventType::from_str(event_type)?;
+        Some(InputEvent {
+            time: TimeVal::new(*sec, *usec),
+            event_code: EventCode::from_str(&event_type, code)?,
+            value: *value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::{EV_KEY, EV_REL, EV_SYN};
+
+    fn event(event_code: EventCode, value: i32) -> InputEvent {
+        InputEvent {
+            time: TimeVal::new(1_700_000_000, 123_456),
+            event_code,
+            value,
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let events = [
+            event(EventCode::EV_KEY(EV_KEY::BTN_EXTRA), 1),
+            event(EventCode::EV_REL(EV_REL::REL_WHEEL), -1),
+            event(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ];
+        for event in events.iter() {
+            let record = Record::new("mouse", event).expect("failed to record event");
+            let line = serde_json::to_string(&record).expect("failed to serialize record");
+            let record: Record = serde_json::from_str(&line).expect("failed to parse record");
+            assert_eq!(record.source, "mouse");
+            assert_eq!(record.event(), Some(*event));
+        }
+    }
+
+    #[test]
+    fn records_are_json_lines() {
+        let record = Record::new("mouse", &event(EventCode::EV_KEY(EV_KEY::BTN_EXTRA), 1))
+            .expect("failed to record event");
+        assert_eq!(
+            serde_json::to_value(&record).expect("failed to serialize record"),
+            serde_json::json!({
+                "source": "mouse",
+                "sec": 1_700_000_000,
+                "usec": 123_456,
+                "type": "EV_KEY",
+                "code": "BTN_EXTRA",
+                "value": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_codes_are_not_recorded() {
+        let unknown = EventCode::EV_UNK {
+            event_type: 1,
+            event_code: 0x2ff,
+        };
+        assert!(Record::new("mouse", &event(unknown, 1)).is_none());
+    }
+}
@@ -0,0 +1,2018 @@
+use crate::config::{self, Chord, Config, Interrupt, LayerMode, Limit, Mode, Rule, Step};
+use crate::grave::Grave;
+use crate::output::Output;
+use evdev_rs::enums::{EventCode, EV_KEY, EV_REL};
+use evdev_rs::{InputEvent, TimeVal};
+use log::debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+/// What is held down on behalf of a single input device.
+#[derive(Default)]
+struct DeviceState {
+    /// Keys and buttons held on the device itself.
+    inputs: HashSet<EventCode>,
+    /// Keys held on sc2input because of events from the device.
+    outputs: HashSet<EV_KEY>,
+    /// The keys held by follow mode rules, by trigger. They are released with the trigger even if
+    /// the layer of the rule which pressed them is no longer active.
+    following: HashMap<EventCode, Vec<EV_KEY>>,
+    /// Whether the device has produced hi-res wheel events.
+    hi_res: bool,
+    /// The hi-res wheel units not yet making up a detent, by the legacy wheel code.
+    wheel: HashMap<EventCode, i32>,
+}
+
+/// A layer which is currently active.
+struct ActiveLayer {
+    /// The index of the layer in the config.
+    index: usize,
+    /// The device which activated the layer.
+    source: String,
+    /// The trigger of the tap-hold rule holding the layer active, if any.
+    held_by: Option<EventCode>,
+}
+
+/// A tap-hold rule whose trigger is pressed, but not yet known to be a tap or a hold.
+struct PendingTapHold<'a> {
+    rule: &'a Rule,
+    source: String,
+    /// When it resolves as a hold.
+    deadline: Duration,
+}
+
+/// A sequence which is waiting for a delay to pass.
+struct RunningSequence<'a> {
+    rule: &'a Rule,
+    source: String,
+    /// The index of the next step.
+    next: usize,
+    /// When the next step is due.
+    due: Duration,
+    /// The keys pressed by the sequence and not released yet.
+    held: Vec<EV_KEY>,
+}
+
+/// A repeat mode rule whose trigger is held.
+struct Repeating<'a> {
+    rule: &'a Rule,
+    source: String,
+    /// When the output is injected next.
+    due: Duration,
+}
+
+/// How recently a rule with a limit fired.
+#[derive(Default)]
+struct LimitState {
+    /// When the rule was last triggered, whether or not it fired.
+    last_triggered: Option<Duration>,
+    /// When the rule fired, as far back as the limit needs.
+    fired: VecDeque<Duration>,
+}
+
+impl LimitState {
+    /// Whether the rule may fire at `now`, recording it if so.
+    fn allow(&mut self, limit: &Limit, now: Duration) -> bool {
+        let Limit {
+            min_interval_ms,
+            max_presses,
+            per_ms,
+            collapse_ms,
+        } = limit;
+        let since = |then: Duration, ms: u64| now < then + Duration::from_millis(ms);
+        let in_burst =
+            collapse_ms.is_some_and(|ms| self.last_triggered.is_some_and(|last| since(last, ms)));
+        self.last_triggered = Some(now);
+        if in_burst {
+            return false;
+        }
+        if min_interval_ms.is_some_and(|ms| self.fired.back().is_some_and(|last| since(*last, ms)))
+        {
+            return false;
+        }
+        if let Some(max_presses) = max_presses {
+            while self
+                .fired
+                .front()
+                .is_some_and(|first| !since(*first, *per_ms))
+            {
+                let _: Option<Duration> = self.fired.pop_front();
+            }
+            if self.fired.len() >= *max_presses {
+                return false;
+            }
+        }
+        self.fired.push_back(now);
+        if self.fired.len() > max_presses.unwrap_or(1).max(1) {
+            let _: Option<Duration> = self.fired.pop_front();
+        }
+        true
+    }
+}
+
+/// An event held back because it may be part of a chord, or came after one which may be.
+struct Buffered {
+    source: String,
+    grabbed: bool,
+    event: InputEvent,
+}
+
+/// A follow mode chord whose output is held until one of its inputs is released.
+struct HeldChord {
+    /// The inputs, with the devices they were pressed on.
+    inputs: Vec<(String, EventCode)>,
+    output: EV_KEY,
+    /// The device the output is held on behalf of.
+    source: String,
+}
+
+/// Turns input events into outputs, according to the rules in the config.
+///
+/// Time is measured with event timestamps, so that replaying a recording behaves like the original
+/// session. Rules which fire after a delay are resolved by the next `process`, or by `tick` once
+/// `next_deadline` has passed.
+pub struct Remapper<'a> {
+    config: &'a Config,
+    devices: HashMap<String, DeviceState>,
+    /// The active layers, most recently activated last.
+    layers: Vec<ActiveLayer>,
+    tap_holds: Vec<PendingTapHold<'a>>,
+    sequences: Vec<RunningSequence<'a>>,
+    repeating: Vec<Repeating<'a>>,
+    /// The state of the rules with limits, by index in the config.
+    limits: HashMap<usize, LimitState>,
+    /// The state of the generator of repeat jitter, which is deterministic so that replays are.
+    jitter: u64,
+    /// Presses which may be the start of a chord, and the events which came after them, in the
+    /// order they happened.
+    chord_buffer: Vec<Buffered>,
+    /// When the buffered presses are processed individually if no chord matched them.
+    chord_deadline: Option<Duration>,
+    held_chords: Vec<HeldChord>,
+    /// Inputs which were part of a chord, whose repeats and release are swallowed.
+    suppressed: HashSet<(String, EventCode)>,
+    grave: Grave,
+}
+
+/// Converts an event timestamp to the time since the epoch.
+pub fn timestamp(time: &TimeVal) -> Duration {
+    Duration::from_secs(time.tv_sec.max(0) as u64)
+        + Duration::from_micros(time.tv_usec.max(0) as u64)
+}
+
+/// The current time on the clock event timestamps are measured with, the realtime clock by
+/// default.
+pub fn now() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Whether `value` is a press of `event_code`, as opposed to a release or key repeat.
+fn is_press(event_code: &EventCode, value: i32) -> bool {
+    match event_code {
+        EventCode::EV_KEY(_) => value == 1,
+        _ => value != 0,
+    }
+}
+
+impl<'a> Remapper<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            devices: HashMap::new(),
+            layers: Vec::new(),
+            tap_holds: Vec::new(),
+            sequences: Vec::new(),
+            repeating: Vec::new(),
+            limits: HashMap::new(),
+            jitter: 0x2545_f491_4f6c_dd1d,
+            chord_buffer: Vec::new(),
+            chord_deadline: None,
+            held_chords: Vec::new(),
+            suppressed: HashSet::new(),
+            grave: Grave::default(),
+        }
+    }
+
+    /// Returns the outputs for `event` from `source`. Events from grabbed devices are forwarded,
+    /// with grave redefined.
+    ///
+    /// Presses which may start a chord are held back until the chord is complete or can no longer
+    /// match, so their outputs may come with a later call.
+    pub fn process(&mut self, source: &str, grabbed: bool, event: InputEvent) -> Vec<Output> {
+        let InputEvent {
+            time,
+            event_code,
+            value,
+        } = event;
+        let now = timestamp(&time);
+        let mut outputs = self.tick(now);
+        if self.suppressed.contains(&(source.to_string(), event_code)) {
+            self.set_held(source, event_code, value);
+            if value == 0 {
+                let _: bool = self.suppressed.remove(&(source.to_string(), event_code));
+                outputs.extend(self.release_chords(|(input_source, input)| {
+                    input_source == source && *input == event_code
+                }));
+            }
+            return outputs;
+        }
+        if !matches!(event_code, EventCode::EV_KEY(_)) {
+            // Motion, wheel and SYN events don't interrupt a chord, but wait behind its presses so
+            // that they are processed in order.
+            if self.chord_buffer.is_empty() {
+                outputs.extend(self.process_event(source, grabbed, event));
+            } else {
+                self.chord_buffer.push(Buffered {
+                    source: source.to_string(),
+                    grabbed,
+                    event,
+                });
+            }
+            return outputs;
+        }
+        if value == 1 {
+            if self.chord_window(event_code).is_none() {
+                outputs.extend(self.flush_chord());
+            }
+            if let Some(window) = self.chord_window(event_code) {
+                if self.chord_buffer.is_empty() {
+                    self.chord_deadline = Some(now + Duration::from_millis(window));
+                }
+                self.chord_buffer.push(Buffered {
+                    source: source.to_string(),
+                    grabbed,
+                    event,
+                });
+                if let Some(chord) = self.complete_chord(now) {
+                    outputs.extend(self.fire_chord(chord));
+                }
+                return outputs;
+            }
+        }
+        outputs.extend(self.flush_chord());
+        outputs.extend(self.process_event(source, grabbed, event));
+        outputs
+    }
+
+    fn process_event(&mut self, source: &str, grabbed: bool, event: InputEvent) -> Vec<Output> {
+        let InputEvent {
+            time,
+            event_code,
+            value,
+        } = event;
+        let mut resolved = self.expire_tap_holds(timestamp(&time));
+        let key_press = matches!(event_code, EventCode::EV_KEY(_)) && value == 1;
+        if key_press {
+            resolved.extend(self.interrupt_tap_holds());
+        }
+        self.set_held(source, event_code, value);
+        let mut outputs = Vec::new();
+        if grabbed {
+            let forwarded = self.grave.map(event);
+            if forwarded != event {
+                debug!("injecting {:?} for grave", forwarded.event_code);
+            }
+            outputs.push(Output::Forward(forwarded));
+        }
+        if value == 0 {
+            outputs.extend(self.release(source, event_code));
+        }
+        let event = match self.wheel_event(source, event) {
+            Some(event) => event,
+            None => {
+                self.track(source, &outputs);
+                resolved.extend(outputs);
+                return resolved;
+            }
+        };
+        let InputEvent {
+            time: _,
+            event_code,
+            value,
+        } = event;
+        let detents = if config::is_wheel(&event_code) {
+            // Not clamp, which panics if max_detents is 0.
+            value
+                .unsigned_abs()
+                .min(self.config.wheel.max_detents)
+                .max(1)
+        } else {
+            1
+        };
+        let activated = self.update_layers(source, &event);
+        let rules = self.resolve(source, &event);
+        if !activated && (key_press || rules.iter().any(|(_, rule)| rule.layer.is_some())) {
+            self.consume_one_shot_layers();
+        }
+        for (index, rule) in rules {
+            let detents = if rule.limit.is_empty() {
+                detents
+            } else {
+                let limit = self.limits.entry(index).or_default();
+                let allowed = (0..detents)
+                    .filter(|_| limit.allow(&rule.limit, timestamp(&time)))
+                    .count() as u32;
+                if allowed < detents {
+                    debug!(
+                        "dropping {} firings of {} over its limit",
+                        detents - allowed,
+                        rule
+                    );
+                }
+                allowed
+            };
+            if detents == 0 {
+                continue;
+            }
+            let output = match rule.output {
+                Some(output) => output,
+                None => {
+                    debug!("running sequence for {:?} from {}", event_code, source);
+                    let length: u64 = rule
+                        .sequence
+                        .iter()
+                        .map(|step| match step {
+                            Step::DelayMs(delay) => *delay,
+                            Step::Tap(_) | Step::Press(_) | Step::Release(_) => 0,
+                        })
+                        .sum();
+                    // The sequence runs once per detent, one after the other.
+                    for detent in 0..detents {
+                        let running = RunningSequence {
+                            rule,
+                            source: source.to_string(),
+                            next: 0,
+                            due: timestamp(&time)
+                                + Duration::from_millis(length * u64::from(detent)),
+                            held: Vec::new(),
+                        };
+                        if detent == 0 {
+                            outputs.extend(self.run_sequence(running));
+                        } else {
+                            self.sequences.push(running);
+                        }
+                    }
+                    continue;
+                }
+            };
+            debug!(
+                "injecting {:?} for {:?} from {}",
+                output, event_code, source
+            );
+            match rule.mode {
+                Mode::Press => outputs.extend((0..detents).map(|_| Output::Press(output))),
+                // Releases come from `release` instead.
+                Mode::Follow | Mode::TapHold if value == 0 => {}
+                Mode::Follow => {
+                    if value == 1 {
+                        self.device(source)
+                            .following
+                            .entry(event_code)
+                            .or_default()
+                            .push(output);
+                    }
+                    outputs.push(Output::Key(output, value));
+                }
+                Mode::Repeat => {
+                    if value == 1 {
+                        self.repeating.push(Repeating {
+                            rule,
+                            source: source.to_string(),
+                            due: timestamp(&time) + Duration::from_millis(rule.repeat_delay_ms),
+                        });
+                        outputs.push(Output::Press(output));
+                    }
+                }
+                Mode::TapHold => {
+                    if is_press(&event_code, value) {
+                        self.tap_holds.push(PendingTapHold {
+                            rule,
+                            source: source.to_string(),
+                            deadline: timestamp(&time) + Duration::from_millis(rule.timeout_ms),
+                        });
+                    }
+                }
+            }
+        }
+        self.track(source, &outputs);
+        resolved.extend(outputs);
+        resolved
+    }
+
+    /// Returns the event rules see for `event`, if any. With `wheel.hi_res`, devices' hi-res wheel
+    /// events are accumulated into detents of REL_WHEEL and REL_HWHEEL, whose own events are then
+    /// ignored.
+    fn wheel_event(&mut self, source: &str, event: InputEvent) -> Option<InputEvent> {
+        let wheel = &self.config.wheel;
+        if !wheel.hi_res {
+            return Some(event);
+        }
+        let legacy = match event.event_code {
+            EventCode::EV_REL(EV_REL::REL_WHEEL) | EventCode::EV_REL(EV_REL::REL_HWHEEL) => {
+                return if self.device(source).hi_res {
+                    None
+                } else {
+                    Some(event)
+                };
+            }
+            EventCode::EV_REL(EV_REL::REL_WHEEL_HI_RES) => EventCode::EV_REL(EV_REL::REL_WHEEL),
+            EventCode::EV_REL(EV_REL::REL_HWHEEL_HI_RES) => EventCode::EV_REL(EV_REL::REL_HWHEEL),
+            _ => return Some(event),
+        };
+        let threshold = wheel.hi_res_threshold;
+        let device = self.device(source);
+        device.hi_res = true;
+        let accumulated = device.wheel.entry(legacy).or_default();
+        // Changing direction starts a new detent.
+        if accumulated.signum() == -event.value.signum() {
+            *accumulated = 0;
+        }
+        *accumulated += event.value;
+        let detents = *accumulated / threshold;
+        *accumulated -= detents * threshold;
+        if detents == 0 {
+            return None;
+        }
+        Some(InputEvent {
+            time: event.time,
+            event_code: legacy,
+            value: detents,
+        })
+    }
+
+    /// When the earliest delayed rule is due, if any.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.tap_holds
+            .iter()
+            .map(|pending| pending.deadline)
+            .chain(self.sequences.iter().map(|running| running.due))
+            .chain(self.repeating.iter().map(|repeating| repeating.due))
+            .chain(self.chord_deadline)
+            .min()
+    }
+
+    /// Returns the outputs of the delayed rules which are due at `now`.
+    pub fn tick(&mut self, now: Duration) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        if self.chord_deadline.is_some_and(|deadline| deadline <= now) {
+            outputs.extend(self.flush_chord());
+        }
+        outputs.extend(self.expire_tap_holds(now));
+        let (due, waiting) = std::mem::take(&mut self.sequences)
+            .into_iter()
+            .partition(|running| running.due <= now);
+        self.sequences = waiting;
+        for running in due {
+            outputs.extend(self.run_sequence(running));
+        }
+        let mut repeating = std::mem::take(&mut self.repeating);
+        for repeating in repeating
+            .iter_mut()
+            .filter(|repeating| repeating.due <= now)
+        {
+            let rule = repeating.rule;
+            outputs.extend(rule.output.map(Output::Press));
+            let jitter = self.next_jitter(rule.repeat_jitter_ms);
+            // Rescheduled from now rather than from when it was due, so that after a late tick,
+            // e.g. after a suspend or the clock being stepped, it fires once instead of once per
+            // missed interval.
+            repeating.due = (now + Duration::from_millis(rule.repeat_interval_ms))
+                .saturating_sub(Duration::from_millis(rule.repeat_jitter_ms))
+                + Duration::from_millis(jitter);
+        }
+        self.repeating = repeating;
+        outputs
+    }
+
+    /// Returns a pseudorandom jitter between 0 and twice `max`, to add to an interval shortened by
+    /// `max`.
+    fn next_jitter(&mut self, max: u64) -> u64 {
+        // xorshift64
+        self.jitter ^= self.jitter << 13;
+        self.jitter ^= self.jitter >> 7;
+        self.jitter ^= self.jitter << 17;
+        self.jitter % (2 * max + 1)
+    }
+
+    /// Runs the steps of a sequence up to its next delay, after which it waits to be resumed by
+    /// `tick`.
+    fn run_sequence(&mut self, mut running: RunningSequence<'a>) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        while let Some(step) = running.rule.sequence.get(running.next) {
+            running.next += 1;
+            match *step {
+                Step::Tap(key) => outputs.push(Output::Press(key)),
+                Step::Press(key) => {
+                    running.held.push(key);
+                    outputs.push(Output::Key(key, 1));
+                }
+                Step::Release(key) => {
+                    running.held.retain(|held| *held != key);
+                    outputs.push(Output::Key(key, 0));
+                }
+                Step::DelayMs(delay) => {
+                    // Relative to when the step was due rather than now, so that delays don't
+                    // accumulate lag.
+                    running.due += Duration::from_millis(delay);
+                    break;
+                }
+            }
+        }
+        self.track(&running.source, &outputs);
+        if running.next < running.rule.sequence.len() {
+            self.sequences.push(running);
+        }
+        outputs
+    }
+
+    fn expire_tap_holds(&mut self, now: Duration) -> Vec<Output> {
+        let (due, pending) = std::mem::take(&mut self.tap_holds)
+            .into_iter()
+            .partition(|pending| pending.deadline <= now);
+        self.tap_holds = pending;
+        due.into_iter()
+            .flat_map(|pending| self.resolve_tap_hold(pending, true))
+            .collect()
+    }
+
+    /// Stops every repeat, as if their triggers were released, e.g. so that the remaining
+    /// deadlines run out once a replay reaches the end of its recording.
+    pub fn stop_repeating(&mut self) {
+        self.repeating.clear();
+    }
+
+    /// Forgets everything held on `source` after its stream ended, returning releases for the
+    /// keys that were held on sc2input on its behalf. Layers it held active are deactivated.
+    pub fn detach(&mut self, source: &str) -> Vec<Output> {
+        let mut releases = self.flush_chord();
+        self.suppressed
+            .retain(|(suppressed, _)| suppressed != source);
+        releases.extend(self.release_chords(|(input, _)| input == source));
+        let config = self.config;
+        self.layers.retain(|active| {
+            active.source != source
+                || (active.held_by.is_none() && config.layers[active.index].mode != LayerMode::Hold)
+        });
+        self.tap_holds.retain(|pending| pending.source != source);
+        self.sequences.retain(|running| running.source != source);
+        self.repeating
+            .retain(|repeating| repeating.source != source);
+        let DeviceState { outputs, .. } = match self.devices.remove(source) {
+            Some(device) => device,
+            None => return releases,
+        };
+        releases.extend(outputs.into_iter().map(|key| {
+            debug!("releasing {:?} held for {}", key, source);
+            Output::Key(key, 0)
+        }));
+        releases
+    }
+
+    /// The keys and buttons whose presses are buffered.
+    fn buffered_presses(&self) -> impl Iterator<Item = EventCode> + '_ {
+        self.chord_buffer
+            .iter()
+            .map(|buffered| buffered.event.event_code)
+            .filter(|code| matches!(code, EventCode::EV_KEY(_)))
+    }
+
+    /// Returns the longest window of the chords which the buffered presses and a press of `code`
+    /// may be the start of, or `None` if there are none.
+    fn chord_window(&self, code: EventCode) -> Option<u64> {
+        let mut pressed: Vec<_> = self.buffered_presses().collect();
+        pressed.push(code);
+        self.config
+            .chords
+            .iter()
+            .filter(|chord| pressed.iter().all(|code| chord.inputs.contains(code)))
+            .map(|chord| chord.window_ms)
+            .max()
+    }
+
+    /// Returns the chord the buffered presses complete, if they were pressed within its window.
+    fn complete_chord(&self, now: Duration) -> Option<&'a Chord> {
+        let first = timestamp(&self.chord_buffer.first()?.event.time);
+        self.config.chords.iter().find(|chord| {
+            chord.inputs.len() == self.buffered_presses().count()
+                && self
+                    .buffered_presses()
+                    .all(|code| chord.inputs.contains(&code))
+                && now <= first + Duration::from_millis(chord.window_ms)
+        })
+    }
+
+    /// Returns the outputs of `chord`, swallowing the buffered presses of its inputs. The other
+    /// buffered events are processed first, in order.
+    fn fire_chord(&mut self, chord: &'a Chord) -> Vec<Output> {
+        self.chord_deadline = None;
+        let mut outputs = Vec::new();
+        let mut inputs = Vec::new();
+        for buffered in std::mem::take(&mut self.chord_buffer) {
+            let Buffered {
+                source,
+                grabbed,
+                event,
+            } = buffered;
+            if let EventCode::EV_KEY(_) = event.event_code {
+                self.set_held(&source, event.event_code, 1);
+                let _: bool = self.suppressed.insert((source.clone(), event.event_code));
+                inputs.push((source, event.event_code));
+            } else {
+                outputs.extend(self.process_event(&source, grabbed, event));
+            }
+        }
+        debug!("injecting {:?} for chord {}", chord.output, chord);
+        match chord.mode {
+            Mode::Follow => {
+                let source = inputs
+                    .last()
+                    .map(|(source, _)| source.clone())
+                    .unwrap_or_default();
+                let held = [Output::Key(chord.output, 1)];
+                self.track(&source, &held);
+                self.held_chords.push(HeldChord {
+                    inputs,
+                    output: chord.output,
+                    source,
+                });
+                outputs.extend(held);
+            }
+            Mode::Press | Mode::TapHold | Mode::Repeat => outputs.push(Output::Press(chord.output)),
+        }
+        outputs
+    }
+
+    /// Processes the buffered events individually, because the presses turned out not to be a
+    /// chord.
+    fn flush_chord(&mut self) -> Vec<Output> {
+        self.chord_deadline = None;
+        std::mem::take(&mut self.chord_buffer)
+            .into_iter()
+            .flat_map(|buffered| {
+                let Buffered {
+                    source,
+                    grabbed,
+                    event,
+                } = buffered;
+                self.process_event(&source, grabbed, event)
+            })
+            .collect()
+    }
+
+    /// Releases the outputs of the held chords with an input for which `is_released` is true.
+    fn release_chords<F>(&mut self, is_released: F) -> Vec<Output>
+    where
+        F: Fn(&(String, EventCode)) -> bool,
+    {
+        let (released, held) = std::mem::take(&mut self.held_chords)
+            .into_iter()
+            .partition(|chord: &HeldChord| chord.inputs.iter().any(&is_released));
+        self.held_chords = held;
+        let mut outputs = Vec::new();
+        for HeldChord { output, source, .. } in released {
+            let release = [Output::Key(output, 0)];
+            self.track(&source, &release);
+            outputs.extend(release);
+        }
+        outputs
+    }
+
+    /// Returns the outputs for the release of `trigger` on `source`: releases of the keys held
+    /// because of it, and the tap of a tap-hold rule still waiting for it.
+    fn release(&mut self, source: &str, trigger: EventCode) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        if let Some(keys) = self.device(source).following.remove(&trigger) {
+            outputs.extend(keys.into_iter().map(|key| Output::Key(key, 0)));
+        }
+        let config = self.config;
+        self.layers.retain(|active| {
+            let held = active.source == source && active.held_by == Some(trigger);
+            if held {
+                debug!("deactivating layer {}", config.layers[active.index].name);
+            }
+            !held
+        });
+        let (released, pending) = std::mem::take(&mut self.tap_holds).into_iter().partition(
+            |pending: &PendingTapHold<'_>| {
+                pending.source == source && pending.rule.trigger == trigger
+            },
+        );
+        self.tap_holds = pending;
+        for pending in released {
+            outputs.extend(self.resolve_tap_hold(pending, false));
+        }
+        self.repeating
+            .retain(|repeating| repeating.source != source || repeating.rule.trigger != trigger);
+        let (cancelled, running) = std::mem::take(&mut self.sequences).into_iter().partition(
+            |running: &RunningSequence<'_>| {
+                running.rule.cancel_on_release
+                    && running.source == source
+                    && running.rule.trigger == trigger
+            },
+        );
+        self.sequences = running;
+        for running in cancelled {
+            debug!("cancelling sequence for {:?} from {}", trigger, source);
+            let releases: Vec<_> = running
+                .held
+                .into_iter()
+                .map(|key| Output::Key(key, 0))
+                .collect();
+            self.track(source, &releases);
+            outputs.extend(releases);
+        }
+        outputs
+    }
+
+    /// Resolves the pending tap-hold rules because another key was pressed, according to their
+    /// interrupt strategies.
+    fn interrupt_tap_holds(&mut self) -> Vec<Output> {
+        let (interrupted, pending) = std::mem::take(&mut self.tap_holds)
+            .into_iter()
+            .partition(|pending| pending.rule.interrupt != Interrupt::Ignore);
+        self.tap_holds = pending;
+        interrupted
+            .into_iter()
+            .flat_map(|pending| {
+                let hold = pending.rule.interrupt == Interrupt::Hold;
+                self.resolve_tap_hold(pending, hold)
+            })
+            .collect()
+    }
+
+    fn resolve_tap_hold(&mut self, pending: PendingTapHold<'a>, hold: bool) -> Vec<Output> {
+        let PendingTapHold {
+            rule,
+            source,
+            deadline: _,
+        } = pending;
+        if !hold {
+            debug!("injecting {:?} for tap of {:?}", rule.output, rule.trigger);
+            return rule.output.into_iter().map(Output::Press).collect();
+        }
+        if let Some(layer) = &rule.hold_layer {
+            let index = self
+                .config
+                .layers
+                .iter()
+                .position(|other| &other.name == layer)
+                .expect("hold_layer refers to an undefined layer");
+            if !self.layers.iter().any(|active| active.index == index) {
+                debug!("activating layer {} for hold of {:?}", layer, rule.trigger);
+                self.layers.push(ActiveLayer {
+                    index,
+                    source: source.clone(),
+                    held_by: Some(rule.trigger),
+                });
+            }
+        }
+        let outputs: Vec<_> = rule
+            .hold
+            .map(|key| {
+                debug!("injecting {:?} for hold of {:?}", key, rule.trigger);
+                self.device(&source)
+                    .following
+                    .entry(rule.trigger)
+                    .or_default()
+                    .push(key);
+                Output::Key(key, 1)
+            })
+            .into_iter()
+            .collect();
+        self.track(&source, &outputs);
+        outputs
+    }
+
+    /// Activates or deactivates the layers `event` controls, returning whether there were any.
+    fn update_layers(&mut self, source: &str, event: &InputEvent) -> bool {
+        let Self { config, layers, .. } = self;
+        let mut activator = false;
+        for (index, layer) in config.layers.iter().enumerate() {
+            if layer.activate != event.event_code
+                || layer
+                    .device
+                    .as_deref()
+                    .is_some_and(|device| device != source)
+            {
+                continue;
+            }
+            activator = true;
+            let position = layers.iter().position(|active| active.index == index);
+            let press = is_press(&event.event_code, event.value);
+            let activate = match (layer.mode, position) {
+                (LayerMode::Hold, None) => press,
+                (LayerMode::Hold, Some(_)) => event.value != 0,
+                (LayerMode::Toggle, position) | (LayerMode::OneShot, position) => {
+                    position.is_none() == press
+                }
+            };
+            match (activate, position) {
+                (true, None) => {
+                    debug!("activating layer {}", layer.name);
+                    layers.push(ActiveLayer {
+                        index,
+                        source: source.to_string(),
+                        held_by: None,
+                    });
+                }
+                (false, Some(position)) => {
+                    debug!("deactivating layer {}", layer.name);
+                    let _: ActiveLayer = layers.remove(position);
+                }
+                (true, Some(_)) | (false, None) => {}
+            }
+        }
+        activator
+    }
+
+    fn consume_one_shot_layers(&mut self) {
+        let config = self.config;
+        self.layers.retain(|active| {
+            let layer = &config.layers[active.index];
+            let one_shot = active.held_by.is_none() && layer.mode == LayerMode::OneShot;
+            if one_shot {
+                debug!("deactivating one-shot layer {}", layer.name);
+            }
+            !one_shot
+        });
+    }
+
+    /// Returns the rules `event` fires in the topmost active layer which has any, falling back to
+    /// the base layer, with their indices in the config.
+    fn resolve(&self, source: &str, event: &InputEvent) -> Vec<(usize, &'a Rule)> {
+        let config = self.config;
+        let is_held = |code: &EventCode| {
+            self.devices
+                .values()
+                .any(|device| device.inputs.contains(code))
+        };
+        self.layers
+            .iter()
+            .rev()
+            .map(|active| Some(config.layers[active.index].name.as_str()))
+            .chain(std::iter::once(None))
+            .map(|layer| {
+                config
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, rule)| {
+                        rule.layer.as_deref() == layer && rule.matches(source, event, is_held)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .find(|rules| !rules.is_empty())
+            .map(|rules| {
+                // Rules which need more inputs held are more specific, and replace the others, so
+                // that e.g. a rule for the wheel while a button is held acts like a chord.
+                let most = rules
+                    .iter()
+                    .map(|(_, rule)| rule.when_held.len())
+                    .max()
+                    .unwrap_or_default();
+                rules
+                    .into_iter()
+                    .filter(|(_, rule)| rule.when_held.len() == most)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Updates the keys held on sc2input on behalf of `source` after writing `outputs`.
+    fn track(&mut self, source: &str, outputs: &[Output]) {
+        let held = &mut self.device(source).outputs;
+        for output in outputs.iter() {
+            let (key, value) = match output {
+                Output::Forward(InputEvent {
+                    time: _,
+                    event_code: EventCode::EV_KEY(key),
+                    value,
+                }) => (key, value),
+                Output::Key(key, value) => (key, value),
+                Output::Forward(_) | Output::Press(_) => continue,
+            };
+            if *value == 0 {
+                let _: bool = held.remove(key);
+            } else {
+                let _: bool = held.insert(*key);
+            }
+        }
+    }
+
+    /// Updates the keys and buttons held on `source` after `event_code` produced `value`.
+    fn set_held(&mut self, source: &str, event_code: EventCode, value: i32) {
+        if let EventCode::EV_KEY(_) = event_code {
+            let inputs = &mut self.device(source).inputs;
+            if value == 0 {
+                let _: bool = inputs.remove(&event_code);
+            } else {
+                let _: bool = inputs.insert(event_code);
+            }
+        }
+    }
+
+    fn device(&mut self, source: &str) -> &mut DeviceState {
+        self.devices.entry(source.to_string()).or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::EventSink as _;
+    use evdev_rs::enums::EV_SYN;
+
+    const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+
+    fn parse(config: &str) -> Config {
+        Config::parse(config, "test.toml".into()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn event(ms: u64, event_code: EventCode, value: i32) -> InputEvent {
+        InputEvent {
+            time: TimeVal::new((ms / 1000) as _, (ms % 1000 * 1000) as _),
+            event_code,
+            value,
+        }
+    }
+
+    fn key(ms: u64, key: EV_KEY, value: i32) -> InputEvent {
+        event(ms, EventCode::EV_KEY(key), value)
+    }
+
+    fn rel(ms: u64, rel: EV_REL, value: i32) -> InputEvent {
+        event(ms, EventCode::EV_REL(rel), value)
+    }
+
+    fn syn(ms: u64) -> InputEvent {
+        event(ms, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0)
+    }
+
+    /// Feeds `events` through `remapper`, collecting what it writes in a `Vec` sink.
+    fn feed(remapper: &mut Remapper<'_>, events: &[(&str, InputEvent)]) -> Vec<Output> {
+        let mut sink = Vec::new();
+        for (source, event) in events {
+            let grabbed = remapper.config.grabs(source);
+            sink.write_all(&remapper.process(source, grabbed, *event))
+                .expect("writing to a Vec can't fail");
+        }
+        sink
+    }
+
+    #[test]
+    fn wheel_presses_end_and_page_down() {
+        let config = parse(DEFAULT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(0, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", syn(0)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, -1)),
+                    ("mouse", syn(10)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_END),
+                Output::Press(EV_KEY::KEY_PAGEDOWN)
+            ]
+        );
+    }
+
+    #[test]
+    fn middle_button_suppresses_wheel() {
+        let config = parse(DEFAULT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_MIDDLE, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", rel(20, EV_REL::REL_WHEEL, -1)),
+                    ("mouse", key(30, EV_KEY::BTN_MIDDLE, 0)),
+                ]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(40, EV_REL::REL_WHEEL, 1))]),
+            vec![Output::Press(EV_KEY::KEY_END)]
+        );
+    }
+
+    #[test]
+    fn extra_button_follows_as_delete() {
+        let config = parse(DEFAULT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_EXTRA, 1))]),
+            vec![Output::Key(EV_KEY::KEY_DELETE, 1)]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(100, EV_KEY::BTN_EXTRA, 0))]),
+            vec![Output::Key(EV_KEY::KEY_DELETE, 0)]
+        );
+    }
+
+    #[test]
+    fn detach_releases_held_outputs() {
+        let config = parse(DEFAULT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_EXTRA, 1))]);
+        assert_eq!(
+            remapper.detach("mouse"),
+            vec![Output::Key(EV_KEY::KEY_DELETE, 0)]
+        );
+        assert_eq!(remapper.detach("mouse"), vec![]);
+    }
+
+    #[test]
+    fn grave_forwards_last_key() {
+        let config = parse(DEFAULT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let events = [
+            key(0, EV_KEY::KEY_Q, 1),
+            syn(0),
+            key(10, EV_KEY::KEY_Q, 0),
+            key(20, EV_KEY::KEY_LEFTSHIFT, 1),
+            key(30, EV_KEY::KEY_GRAVE, 1),
+            key(40, EV_KEY::KEY_GRAVE, 0),
+            key(50, EV_KEY::KEY_LEFTSHIFT, 0),
+        ];
+        let events: Vec<_> = events.iter().map(|event| ("keyboard", *event)).collect();
+        assert_eq!(
+            feed(&mut remapper, &events),
+            vec![
+                Output::Forward(key(0, EV_KEY::KEY_Q, 1)),
+                Output::Forward(syn(0)),
+                Output::Forward(key(10, EV_KEY::KEY_Q, 0)),
+                Output::Forward(key(20, EV_KEY::KEY_LEFTSHIFT, 1)),
+                Output::Forward(key(30, EV_KEY::KEY_Q, 1)),
+                Output::Forward(key(40, EV_KEY::KEY_Q, 0)),
+                Output::Forward(key(50, EV_KEY::KEY_LEFTSHIFT, 0)),
+            ]
+        );
+    }
+
+    fn layer_config(mode: &str) -> Config {
+        parse(&format!(
+            r#"
+            [[layers]]
+            name = "alt"
+            activate = "BTN_SIDE"
+            mode = "{}"
+
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_END"
+
+            [[rules]]
+            trigger = "BTN_EXTRA"
+            output = "KEY_DELETE"
+
+            [[rules]]
+            layer = "alt"
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_UP"
+            "#,
+            mode
+        ))
+    }
+
+    #[test]
+    fn hold_layer_is_active_while_held() {
+        let config = layer_config("hold");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(0, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(10, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", rel(20, EV_REL::REL_WHEEL, 1)),
+                    // Falls back to the base layer, which has a rule for it.
+                    ("mouse", key(30, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", key(40, EV_KEY::BTN_EXTRA, 0)),
+                    ("mouse", rel(50, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(60, EV_KEY::BTN_SIDE, 0)),
+                    ("mouse", rel(70, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_END),
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_DELETE),
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_END),
+            ]
+        );
+    }
+
+    #[test]
+    fn toggle_layer_switches_with_each_press() {
+        let config = layer_config("toggle");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(10, EV_KEY::BTN_SIDE, 0)),
+                    ("mouse", rel(20, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", rel(30, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(40, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(50, EV_KEY::BTN_SIDE, 0)),
+                    ("mouse", rel(60, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_END),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_layer_lasts_one_firing() {
+        let config = layer_config("one_shot");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(10, EV_KEY::BTN_SIDE, 0)),
+                    ("mouse", rel(20, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", rel(30, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_END),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_layer_ends_with_a_key_press() {
+        let config = layer_config("one_shot");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(10, EV_KEY::BTN_SIDE, 0)),
+                    ("mouse", key(20, EV_KEY::BTN_LEFT, 1)),
+                    ("mouse", key(30, EV_KEY::BTN_LEFT, 0)),
+                    ("mouse", rel(40, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_END)]
+        );
+    }
+
+    fn tap_hold_config(interrupt: &str) -> Config {
+        parse(&format!(
+            r#"
+            [[layers]]
+            name = "alt"
+            activate = "KEY_F12"
+
+            [[rules]]
+            trigger = "BTN_SIDE"
+            mode = "tap_hold"
+            output = "KEY_F1"
+            hold = "KEY_LEFTCTRL"
+            timeout_ms = 200
+            interrupt = "{}"
+
+            [[rules]]
+            trigger = "BTN_EXTRA"
+            output = "KEY_DELETE"
+
+            [[rules]]
+            trigger = "BTN_FORWARD"
+            mode = "tap_hold"
+            output = "KEY_F2"
+            hold_layer = "alt"
+
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_END"
+
+            [[rules]]
+            layer = "alt"
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_UP"
+            "#,
+            interrupt
+        ))
+    }
+
+    #[test]
+    fn tap_hold_taps_on_quick_release() {
+        let config = tap_hold_config("hold");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]),
+            vec![]
+        );
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(200)));
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(150, EV_KEY::BTN_SIDE, 0))]),
+            vec![Output::Press(EV_KEY::KEY_F1)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn tap_hold_holds_after_timeout() {
+        let config = tap_hold_config("hold");
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]);
+        assert_eq!(remapper.tick(Duration::from_millis(199)), vec![]);
+        assert_eq!(
+            remapper.tick(Duration::from_millis(200)),
+            vec![Output::Key(EV_KEY::KEY_LEFTCTRL, 1)]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(500, EV_KEY::BTN_SIDE, 0))]),
+            vec![Output::Key(EV_KEY::KEY_LEFTCTRL, 0)]
+        );
+    }
+
+    #[test]
+    fn tap_hold_interrupted_as_hold() {
+        let config = tap_hold_config("hold");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(50, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", key(60, EV_KEY::BTN_EXTRA, 0)),
+                    ("mouse", key(70, EV_KEY::BTN_SIDE, 0)),
+                ]
+            ),
+            vec![
+                Output::Key(EV_KEY::KEY_LEFTCTRL, 1),
+                Output::Press(EV_KEY::KEY_DELETE),
+                Output::Key(EV_KEY::KEY_LEFTCTRL, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_hold_interrupted_as_tap() {
+        let config = tap_hold_config("tap");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(50, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", key(60, EV_KEY::BTN_EXTRA, 0)),
+                    ("mouse", key(70, EV_KEY::BTN_SIDE, 0)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_F1),
+                Output::Press(EV_KEY::KEY_DELETE),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_hold_ignores_interrupts() {
+        let config = tap_hold_config("ignore");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(50, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", key(60, EV_KEY::BTN_EXTRA, 0)),
+                    ("mouse", key(70, EV_KEY::BTN_SIDE, 0)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_DELETE),
+                Output::Press(EV_KEY::KEY_F1),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_hold_holds_layer() {
+        let config = tap_hold_config("hold");
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_FORWARD, 1))]);
+        assert_eq!(remapper.tick(Duration::from_millis(200)), vec![]);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(250, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(300, EV_KEY::BTN_FORWARD, 0)),
+                    ("mouse", rel(350, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_UP),
+                Output::Press(EV_KEY::KEY_END),
+            ]
+        );
+    }
+
+    const CHORD_CONFIG: &str = r#"
+        [[chords]]
+        inputs = ["BTN_SIDE", "BTN_EXTRA"]
+        output = "KEY_F5"
+        window_ms = 40
+
+        [[chords]]
+        inputs = ["KEY_A", "KEY_S"]
+        output = "KEY_F6"
+        mode = "follow"
+
+        [[rules]]
+        trigger = "BTN_SIDE"
+        output = "KEY_F1"
+
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = 1
+        output = "KEY_END"
+
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = 1
+        output = "KEY_UP"
+        when_held = ["BTN_SIDE"]
+
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = 1
+        output = "KEY_F7"
+        when_held = ["BTN_RIGHT"]
+    "#;
+
+    #[test]
+    fn chord_replaces_individual_outputs() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", syn(0)),
+                    ("mouse", key(20, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", syn(20)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_F5)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(100, EV_KEY::BTN_EXTRA, 0)),
+                    ("mouse", key(110, EV_KEY::BTN_SIDE, 0)),
+                ]
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn chord_times_out_into_individual_outputs() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]),
+            vec![]
+        );
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(40)));
+        assert_eq!(
+            remapper.tick(Duration::from_millis(40)),
+            vec![Output::Press(EV_KEY::KEY_F1)]
+        );
+        // Too late to complete the chord.
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(50, EV_KEY::BTN_EXTRA, 1))]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn follow_chord_is_held_until_an_input_is_released() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("keyboard", key(0, EV_KEY::KEY_A, 1)),
+                    ("keyboard", syn(0)),
+                    ("keyboard", key(10, EV_KEY::KEY_S, 1)),
+                    ("keyboard", syn(10)),
+                ]
+            ),
+            vec![
+                Output::Forward(syn(0)),
+                Output::Key(EV_KEY::KEY_F6, 1),
+                Output::Forward(syn(10)),
+            ]
+        );
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("keyboard", key(100, EV_KEY::KEY_A, 0)),
+                    ("keyboard", key(110, EV_KEY::KEY_S, 0)),
+                ]
+            ),
+            vec![Output::Key(EV_KEY::KEY_F6, 0)]
+        );
+    }
+
+    #[test]
+    fn events_wait_behind_buffered_presses() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("keyboard", key(0, EV_KEY::KEY_A, 1)),
+                    ("keyboard", syn(0)),
+                    ("keyboard", key(30, EV_KEY::KEY_A, 0)),
+                    ("keyboard", syn(30)),
+                ]
+            ),
+            vec![
+                Output::Forward(key(0, EV_KEY::KEY_A, 1)),
+                Output::Forward(syn(0)),
+                Output::Forward(key(30, EV_KEY::KEY_A, 0)),
+                Output::Forward(syn(30)),
+            ]
+        );
+        // The wheel sees the buffered button as held.
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(100, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", rel(110, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            remapper.tick(Duration::from_millis(140)),
+            vec![Output::Press(EV_KEY::KEY_F1), Output::Press(EV_KEY::KEY_UP)]
+        );
+    }
+
+    #[test]
+    fn events_behind_completed_chord_see_its_inputs_held() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(20, EV_KEY::BTN_EXTRA, 1)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_UP), Output::Press(EV_KEY::KEY_F5)]
+        );
+    }
+
+    #[test]
+    fn gated_rules_replace_ungated_rules() {
+        let config = parse(CHORD_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_RIGHT, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", key(20, EV_KEY::BTN_RIGHT, 0)),
+                    ("mouse", rel(30, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_F7),
+                Output::Press(EV_KEY::KEY_END)
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_held_on_one_device_gate_rules_of_another() {
+        let config = parse(
+            r#"
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_END"
+
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_F1"
+            when_held = ["KEY_LEFTSHIFT"]
+            "#,
+        );
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("keyboard", key(0, EV_KEY::KEY_LEFTSHIFT, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, 1)),
+                    ("keyboard", key(20, EV_KEY::KEY_LEFTSHIFT, 0)),
+                    ("mouse", rel(30, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Forward(key(0, EV_KEY::KEY_LEFTSHIFT, 1)),
+                Output::Press(EV_KEY::KEY_F1),
+                Output::Forward(key(20, EV_KEY::KEY_LEFTSHIFT, 0)),
+                Output::Press(EV_KEY::KEY_END),
+            ]
+        );
+    }
+
+    const SEQUENCE_CONFIG: &str = r#"
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = 1
+        sequence = ["KEY_1", { delay_ms = 20 }, "KEY_5", { delay_ms = 20 }, "KEY_1"]
+
+        [[rules]]
+        trigger = "BTN_SIDE"
+        sequence = [
+            { press = "KEY_LEFTSHIFT" }, { delay_ms = 100 }, "KEY_A", { release = "KEY_LEFTSHIFT" },
+        ]
+        cancel_on_release = true
+
+        [[rules]]
+        trigger = "BTN_EXTRA"
+        sequence = [
+            { press = "KEY_LEFTSHIFT" }, { delay_ms = 100 }, "KEY_A", { release = "KEY_LEFTSHIFT" },
+        ]
+    "#;
+
+    #[test]
+    fn sequence_runs_steps_after_delays() {
+        let config = parse(SEQUENCE_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(0, EV_REL::REL_WHEEL, 1))]),
+            vec![Output::Press(EV_KEY::KEY_1)]
+        );
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(20)));
+        // A later event resumes the sequence too.
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", syn(25))]),
+            vec![Output::Press(EV_KEY::KEY_5)]
+        );
+        // The next delay counts from when the step was due.
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(40)));
+        assert_eq!(
+            remapper.tick(Duration::from_millis(40)),
+            vec![Output::Press(EV_KEY::KEY_1)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn sequence_cancelled_on_release() {
+        let config = parse(SEQUENCE_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]),
+            vec![Output::Key(EV_KEY::KEY_LEFTSHIFT, 1)]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(50, EV_KEY::BTN_SIDE, 0))]),
+            vec![Output::Key(EV_KEY::KEY_LEFTSHIFT, 0)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+        assert_eq!(remapper.detach("mouse"), vec![]);
+    }
+
+    #[test]
+    fn sequence_outlives_release() {
+        let config = parse(SEQUENCE_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_EXTRA, 1)),
+                    ("mouse", key(50, EV_KEY::BTN_EXTRA, 0)),
+                ]
+            ),
+            vec![Output::Key(EV_KEY::KEY_LEFTSHIFT, 1)]
+        );
+        assert_eq!(
+            remapper.tick(Duration::from_millis(100)),
+            vec![
+                Output::Press(EV_KEY::KEY_A),
+                Output::Key(EV_KEY::KEY_LEFTSHIFT, 0)
+            ]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn detach_stops_sequences_and_releases_their_keys() {
+        let config = parse(SEQUENCE_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_EXTRA, 1))]);
+        assert_eq!(
+            remapper.detach("mouse"),
+            vec![Output::Key(EV_KEY::KEY_LEFTSHIFT, 0)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    const REPEAT_CONFIG: &str = r#"
+        [[rules]]
+        trigger = "BTN_SIDE"
+        output = "KEY_Q"
+        mode = "repeat"
+        repeat_delay_ms = 250
+        repeat_interval_ms = 50
+
+        [[rules]]
+        trigger = "BTN_EXTRA"
+        output = "KEY_W"
+        mode = "repeat"
+        repeat_delay_ms = 100
+        repeat_interval_ms = 40
+        repeat_jitter_ms = 5
+    "#;
+
+    #[test]
+    fn repeat_fires_until_release() {
+        let config = parse(REPEAT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]),
+            vec![Output::Press(EV_KEY::KEY_Q)]
+        );
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(250)));
+        assert_eq!(
+            remapper.tick(Duration::from_millis(250)),
+            vec![Output::Press(EV_KEY::KEY_Q)]
+        );
+        assert_eq!(remapper.next_deadline(), Some(Duration::from_millis(300)));
+        assert_eq!(
+            remapper.tick(Duration::from_millis(300)),
+            vec![Output::Press(EV_KEY::KEY_Q)]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", key(320, EV_KEY::BTN_SIDE, 0))]),
+            vec![]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn repeat_does_not_catch_up_after_a_late_tick() {
+        let config = parse(REPEAT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]);
+        assert_eq!(
+            remapper.tick(Duration::from_secs(3600)),
+            vec![Output::Press(EV_KEY::KEY_Q)]
+        );
+        assert_eq!(
+            remapper.next_deadline(),
+            Some(Duration::from_secs(3600) + Duration::from_millis(50))
+        );
+    }
+
+    #[test]
+    fn repeat_jitter_stays_within_bounds() {
+        let config = parse(REPEAT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_EXTRA, 1))]);
+        let mut previous = remapper.next_deadline().expect("repeat not scheduled");
+        assert_eq!(previous, Duration::from_millis(100));
+        let mut intervals = HashSet::new();
+        for _ in 0..100 {
+            assert_eq!(remapper.tick(previous), vec![Output::Press(EV_KEY::KEY_W)]);
+            let next = remapper.next_deadline().expect("repeat not scheduled");
+            let interval = (next - previous).as_millis();
+            assert!((35..=45).contains(&interval), "interval {}ms", interval);
+            let _: bool = intervals.insert(interval);
+            previous = next;
+        }
+        assert!(intervals.len() > 1, "no jitter");
+    }
+
+    #[test]
+    fn detach_stops_repeating() {
+        let config = parse(REPEAT_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        let _: Vec<Output> = feed(&mut remapper, &[("mouse", key(0, EV_KEY::BTN_SIDE, 1))]);
+        assert_eq!(remapper.detach("mouse"), vec![]);
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn other_delayed_rules_outlive_stopped_repeats() {
+        let config = parse(&format!(
+            r#"
+            {}
+
+            [[rules]]
+            trigger = "BTN_LEFT"
+            sequence = ["KEY_1", {{ delay_ms = 20 }}, "KEY_2"]
+
+            [[rules]]
+            trigger = "BTN_FORWARD"
+            mode = "tap_hold"
+            output = "KEY_F1"
+            hold = "KEY_LEFTCTRL"
+            timeout_ms = 200
+            "#,
+            REPEAT_CONFIG
+        ));
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(0, EV_KEY::BTN_SIDE, 1)),
+                    ("mouse", key(0, EV_KEY::BTN_LEFT, 1)),
+                    ("mouse", key(10, EV_KEY::BTN_FORWARD, 1)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_Q), Output::Press(EV_KEY::KEY_1)]
+        );
+        remapper.stop_repeating();
+        let mut outputs = Vec::new();
+        while let Some(deadline) = remapper.next_deadline() {
+            outputs.extend(remapper.tick(deadline));
+        }
+        assert_eq!(
+            outputs,
+            vec![
+                Output::Press(EV_KEY::KEY_2),
+                Output::Key(EV_KEY::KEY_LEFTCTRL, 1)
+            ]
+        );
+        assert_eq!(
+            remapper.detach("mouse"),
+            vec![Output::Key(EV_KEY::KEY_LEFTCTRL, 0)]
+        );
+    }
+
+    const WHEEL_CONFIG: &str = r#"
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = 1
+        output = "KEY_END"
+
+        [[rules]]
+        trigger = "REL_WHEEL"
+        value = -1
+        sequence = ["KEY_1", { delay_ms = 20 }, "KEY_2"]
+    "#;
+
+    #[test]
+    fn wheel_rules_fire_per_detent_up_to_max_detents() {
+        let config = parse(&format!("[wheel]\nmax_detents = 3\n{}", WHEEL_CONFIG));
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(0, EV_REL::REL_WHEEL, 2))]),
+            vec![Output::Press(EV_KEY::KEY_END); 2]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(10, EV_REL::REL_WHEEL, 7))]),
+            vec![Output::Press(EV_KEY::KEY_END); 3]
+        );
+    }
+
+    #[test]
+    fn tilt_wheel_fires_per_detent_unless_gated() {
+        let config = parse(
+            r#"
+            [wheel]
+            max_detents = 3
+
+            [[rules]]
+            trigger = "REL_HWHEEL"
+            value = -1
+            output = "KEY_F2"
+            unless_held = ["BTN_MIDDLE"]
+
+            [[rules]]
+            trigger = "REL_HWHEEL"
+            value = 1
+            output = "KEY_F3"
+            unless_held = ["BTN_MIDDLE"]
+            "#,
+        );
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(0, EV_REL::REL_HWHEEL, -1)),
+                    ("mouse", rel(10, EV_REL::REL_HWHEEL, 1)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_F2), Output::Press(EV_KEY::KEY_F3)]
+        );
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", key(20, EV_KEY::BTN_MIDDLE, 1)),
+                    ("mouse", rel(30, EV_REL::REL_HWHEEL, 1)),
+                    ("mouse", key(40, EV_KEY::BTN_MIDDLE, 0)),
+                ]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(50, EV_REL::REL_HWHEEL, -5))]),
+            vec![Output::Press(EV_KEY::KEY_F2); 3]
+        );
+    }
+
+    #[test]
+    fn wheel_sequences_run_once_per_detent_in_turn() {
+        let config = parse(WHEEL_CONFIG);
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(0, EV_REL::REL_WHEEL, -2))]),
+            vec![Output::Press(EV_KEY::KEY_1)]
+        );
+        assert_eq!(
+            remapper.tick(Duration::from_millis(20)),
+            vec![Output::Press(EV_KEY::KEY_2), Output::Press(EV_KEY::KEY_1)]
+        );
+        assert_eq!(
+            remapper.tick(Duration::from_millis(40)),
+            vec![Output::Press(EV_KEY::KEY_2)]
+        );
+        assert_eq!(remapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn hi_res_wheel_accumulates_detents() {
+        let config = parse(&format!(
+            "[wheel]\nhi_res = true\nhi_res_threshold = 60\n{}",
+            WHEEL_CONFIG
+        ));
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(0, EV_REL::REL_WHEEL_HI_RES, 30)),
+                    // Ignored in favour of the hi-res events.
+                    ("mouse", rel(0, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL_HI_RES, 30)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_END)]
+        );
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[("mouse", rel(20, EV_REL::REL_WHEEL_HI_RES, 150))]
+            ),
+            vec![Output::Press(EV_KEY::KEY_END); 2]
+        );
+        // Changing direction drops the 30 units left over.
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(30, EV_REL::REL_WHEEL_HI_RES, -30)),
+                    ("mouse", rel(40, EV_REL::REL_WHEEL_HI_RES, -30)),
+                ]
+            ),
+            vec![Output::Press(EV_KEY::KEY_1)]
+        );
+        assert_eq!(
+            remapper.tick(Duration::from_millis(60)),
+            vec![Output::Press(EV_KEY::KEY_2)]
+        );
+        // Devices without hi-res events still fire on the legacy ones.
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[("trackball", rel(100, EV_REL::REL_WHEEL, 1))]
+            ),
+            vec![Output::Press(EV_KEY::KEY_END)]
+        );
+    }
+
+    fn limit_config(limit: &str) -> Config {
+        parse(&format!(
+            r#"
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = 1
+            output = "KEY_END"
+            limit = {}
+
+            [[rules]]
+            trigger = "REL_WHEEL"
+            value = -1
+            output = "KEY_PAGEDOWN"
+            limit = {}
+            "#,
+            limit, limit
+        ))
+    }
+
+    /// Scrolls up once at each of `times`, returning how many of them fired.
+    fn scroll_up(remapper: &mut Remapper<'_>, times: &[u64]) -> usize {
+        let events: Vec<_> = times
+            .iter()
+            .map(|ms| ("mouse", rel(*ms, EV_REL::REL_WHEEL, 1)))
+            .collect();
+        feed(remapper, &events).len()
+    }
+
+    #[test]
+    fn limit_min_interval() {
+        let config = limit_config("{ min_interval_ms = 100 }");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(scroll_up(&mut remapper, &[0, 50, 99]), 1);
+        assert_eq!(scroll_up(&mut remapper, &[100, 150, 200]), 2);
+    }
+
+    #[test]
+    fn limit_max_presses_per_window() {
+        let config = limit_config("{ max_presses = 2, per_ms = 100 }");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(scroll_up(&mut remapper, &[0, 10, 20, 90]), 2);
+        // The window slides: the press at 10 counts until 110.
+        assert_eq!(scroll_up(&mut remapper, &[100, 105, 109]), 1);
+        assert_eq!(scroll_up(&mut remapper, &[300, 301, 302]), 2);
+        // Each detent counts.
+        assert_eq!(
+            feed(&mut remapper, &[("mouse", rel(500, EV_REL::REL_WHEEL, 3))]),
+            vec![Output::Press(EV_KEY::KEY_END); 2]
+        );
+    }
+
+    #[test]
+    fn limit_collapses_bursts() {
+        let config = limit_config("{ collapse_ms = 30 }");
+        let mut remapper = Remapper::new(&config);
+        // Each event extends the burst, however long it lasts.
+        assert_eq!(scroll_up(&mut remapper, &[0, 20, 40, 60, 80]), 1);
+        assert_eq!(scroll_up(&mut remapper, &[110, 120]), 1);
+    }
+
+    #[test]
+    fn limits_are_per_rule() {
+        let config = limit_config("{ min_interval_ms = 100 }");
+        let mut remapper = Remapper::new(&config);
+        assert_eq!(
+            feed(
+                &mut remapper,
+                &[
+                    ("mouse", rel(0, EV_REL::REL_WHEEL, 1)),
+                    ("mouse", rel(10, EV_REL::REL_WHEEL, -1)),
+                    ("mouse", rel(20, EV_REL::REL_WHEEL, 1)),
+                ]
+            ),
+            vec![
+                Output::Press(EV_KEY::KEY_END),
+                Output::Press(EV_KEY::KEY_PAGEDOWN)
+            ]
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use futures::channel::mpsc;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Sends every SIGINT, SIGTERM or SIGHUP received, instead of letting it kill the process.
+pub fn watch() -> std::io::Result<mpsc::UnboundedReceiver<i32>> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let (sender, receiver) = mpsc::unbounded();
+    let _: std::thread::JoinHandle<()> = std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if sender.unbounded_send(signal).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}